@@ -0,0 +1,344 @@
+//! bao-style verified streaming of blake3 hashed values.
+//!
+//! The value is sent as its blake3 tree in pre-order: each parent node is the
+//! chaining values of its two children, followed by the left then right subtree,
+//! down to 1KiB chunks of content. The reader checks every parent and chunk
+//! against the root hash as it arrives, so a bad peer is caught at the first bad chunk
+//! rather than after the whole value has been downloaded.
+//!
+//! A range of the value is sent as just the parents and chunks on the paths to the
+//! chunks it overlaps, which is enough to verify those chunks against the root.
+use std::{io, ops::Range};
+
+use blake3::{
+    guts::{parent_cv, ChunkState, CHUNK_LEN},
+    Hash,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+
+/// Length of an encoded parent node, the chaining values of its two children
+const PARENT_LEN: usize = 64;
+
+/// The parent nodes of a value's tree, in pre-order.
+///
+/// With the content itself, this is enough to encode any range without rehashing.
+pub struct Outboard {
+    parents: Vec<u8>,
+    content_len: u64,
+    root: Hash,
+}
+
+/// Length of the encoding of `content_len` bytes of content
+pub fn encoded_len(content_len: u64) -> u64 {
+    content_len + parents(content_len) * PARENT_LEN as u64
+}
+
+/// Encodes `content` in pre-order, returning the encoding and its blake3 hash
+pub fn encode(content: &[u8]) -> (Vec<u8>, Hash) {
+    let outboard = Outboard::new(content);
+    let encoded = outboard.encode_range(content, 0..content.len() as u64);
+    (encoded, outboard.root)
+}
+
+/// The byte range of the chunks overlapping `range`, which is what a range encoding carries
+pub fn chunk_range(range: Range<u64>, content_len: u64) -> Range<u64> {
+    let chunk = CHUNK_LEN as u64;
+    let start = (range.start / chunk * chunk).min(content_len);
+    if range.is_empty() {
+        return start..start;
+    }
+    let end = range.end.div_ceil(chunk) * chunk;
+    start..end.min(content_len)
+}
+
+impl Outboard {
+    pub fn new(content: &[u8]) -> Self {
+        let mut parents = Vec::with_capacity(parents(content.len() as u64) as usize * PARENT_LEN);
+        let root = hash_subtree(content, 0, true, &mut parents);
+        Self {
+            parents,
+            content_len: content.len() as u64,
+            root,
+        }
+    }
+
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub fn content_len(&self) -> u64 {
+        self.content_len
+    }
+
+    /// Encodes the parents and chunks needed to verify the chunks overlapping `range`.
+    ///
+    /// `chunks` is the content in [`chunk_range`] of `range`.
+    pub fn encode_range(&self, chunks: &[u8], range: Range<u64>) -> Vec<u8> {
+        self.pieces(chunks, range).collect::<Vec<_>>().concat()
+    }
+
+    /// Writes the encoding of `range` as [`encode_range`](Self::encode_range) would return it,
+    /// without copying the content into a separate buffer
+    pub async fn write_range(
+        &self,
+        chunks: &[u8],
+        range: Range<u64>,
+        w: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        let mut w = BufWriter::new(w);
+        for piece in self.pieces(chunks, range) {
+            w.write_all(piece).await?;
+        }
+        w.flush().await
+    }
+
+    /// The parents and chunks of the encoding of `range`, in order
+    fn pieces<'a>(&'a self, chunks: &'a [u8], range: Range<u64>) -> Pieces<'a> {
+        Pieces {
+            outboard: self,
+            chunks,
+            chunks_start: chunk_range(range.clone(), self.content_len).start,
+            range,
+            stack: vec![(0, self.content_len)],
+            parent: 0,
+        }
+    }
+}
+
+struct Pieces<'a> {
+    outboard: &'a Outboard,
+    chunks: &'a [u8],
+    chunks_start: u64,
+    range: Range<u64>,
+    /// Subtrees still to encode, as (content offset, content length)
+    stack: Vec<(u64, u64)>,
+    /// Offset of the next subtree's first parent in the outboard
+    parent: usize,
+}
+
+impl<'a> Iterator for Pieces<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        while let Some((offset, len)) = self.stack.pop() {
+            if !overlaps(offset, len, &self.range) {
+                self.parent += parents(len) as usize * PARENT_LEN;
+                continue;
+            }
+            if len <= CHUNK_LEN as u64 {
+                let start = (offset - self.chunks_start) as usize;
+                return Some(&self.chunks[start..start + len as usize]);
+            }
+            let parent = &self.outboard.parents[self.parent..self.parent + PARENT_LEN];
+            self.parent += PARENT_LEN;
+            let left_len = left_len(len);
+            self.stack.push((offset + left_len, len - left_len));
+            self.stack.push((offset, left_len));
+            return Some(parent);
+        }
+        None
+    }
+}
+
+/// Hashes a subtree, appending its parent nodes to `parents` in pre-order
+fn hash_subtree(content: &[u8], chunk: u64, is_root: bool, parents: &mut Vec<u8>) -> Hash {
+    if content.len() <= CHUNK_LEN {
+        return ChunkState::new(chunk).update(content).finalize(is_root);
+    }
+
+    // the children are only known after hashing them, so leave room for the parent
+    let parent = parents.len();
+    parents.extend_from_slice(&[0; PARENT_LEN]);
+    let (left, right) = content.split_at(left_len(content.len() as u64) as usize);
+    let left_cv = hash_subtree(left, chunk, false, parents);
+    let right_chunk = chunk + (left.len() / CHUNK_LEN) as u64;
+    let right_cv = hash_subtree(right, right_chunk, false, parents);
+    parents[parent..parent + 32].copy_from_slice(left_cv.as_bytes());
+    parents[parent + 32..parent + PARENT_LEN].copy_from_slice(right_cv.as_bytes());
+    parent_cv(&left_cv, &right_cv, is_root)
+}
+
+/// Reads the encoding of `content_len` bytes, checking each node against `root` as it arrives.
+///
+/// Fails at the first parent or chunk that does not match, without reading any further.
+pub async fn decode(
+    r: &mut (impl AsyncRead + Unpin),
+    root: &Hash,
+    content_len: u64,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    decode_range(r, root, content_len, 0..content_len).await
+}
+
+/// Reads the encoding of `range` of a value, checking each node against `root` as it arrives.
+///
+/// Returns the content in [`chunk_range`] of `range`.
+pub async fn decode_range(
+    r: &mut (impl AsyncRead + Unpin),
+    root: &Hash,
+    content_len: u64,
+    range: Range<u64>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut content = Vec::new();
+    let mut chunk = [0; CHUNK_LEN];
+    let mut parent = [0; PARENT_LEN];
+
+    // subtrees still to read, as (chaining value, content offset, content length, is root)
+    let mut stack = vec![(*root, 0, content_len, true)];
+    while let Some((cv, offset, len, is_root)) = stack.pop() {
+        if !overlaps(offset, len, &range) {
+            continue;
+        }
+        let index = offset / CHUNK_LEN as u64;
+        if len <= CHUNK_LEN as u64 {
+            let chunk = &mut chunk[..len as usize];
+            r.read_exact(chunk).await?;
+            if ChunkState::new(index).update(chunk).finalize(is_root) != cv {
+                return Err(format!("chunk {index} does not match its blake3 hash").into());
+            }
+            content.extend_from_slice(chunk);
+            continue;
+        }
+
+        r.read_exact(&mut parent).await?;
+        let left_cv = Hash::from(<[u8; 32]>::try_from(&parent[..32]).unwrap());
+        let right_cv = Hash::from(<[u8; 32]>::try_from(&parent[32..]).unwrap());
+        if parent_cv(&left_cv, &right_cv, is_root) != cv {
+            return Err(format!("parent of chunk {index} does not match its blake3 hash").into());
+        }
+        let left_len = left_len(len);
+        stack.push((right_cv, offset + left_len, len - left_len, false));
+        stack.push((left_cv, offset, left_len, false));
+    }
+    Ok(content)
+}
+
+/// Whether the subtree covering `len` bytes from `offset` is part of the encoding of `range`.
+/// The empty root chunk of an empty value always is, so that its hash is still checked.
+fn overlaps(offset: u64, len: u64, range: &Range<u64>) -> bool {
+    len == 0 || (!range.is_empty() && offset < range.end && range.start < offset + len)
+}
+
+/// Number of parent nodes in the tree of `content_len` bytes
+fn parents(content_len: u64) -> u64 {
+    content_len.div_ceil(CHUNK_LEN as u64).max(1) - 1
+}
+
+/// Content length of the left subtree of a parent covering `content_len` bytes.
+///
+/// The left subtree is the largest power of two number of full chunks
+/// that leaves at least one byte for the right.
+fn left_len(content_len: u64) -> u64 {
+    let full_chunks = (content_len - 1) / CHUNK_LEN as u64;
+    let largest_power_of_two = 1 << (63 - full_chunks.leading_zeros());
+    largest_power_of_two * CHUNK_LEN as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lengths around chunk and subtree boundaries
+    const LENS: [u64; 12] = [
+        0,
+        1,
+        1023,
+        1024,
+        1025,
+        2048,
+        2049,
+        3072,
+        5000,
+        1 << 20,
+        (1 << 20) + 7,
+        3_000_001,
+    ];
+
+    fn content(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn ranges(len: u64) -> Vec<Range<u64>> {
+        [
+            0..len,
+            0..1,
+            len / 3..len / 2,
+            len.saturating_sub(1)..len,
+            1000..1030,
+            5000..9000,
+            len / 2..len / 2,
+        ]
+        .into_iter()
+        .map(|range| range.start.min(len)..range.end.min(len))
+        .collect()
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        for len in LENS {
+            let content = content(len);
+            let (encoded, root) = encode(&content);
+            assert_eq!(root, blake3::hash(&content), "len {len}");
+            assert_eq!(encoded.len() as u64, encoded_len(len), "len {len}");
+            let decoded = decode(&mut &encoded[..], &root, len).await.unwrap();
+            assert_eq!(decoded, content, "len {len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn range_round_trip() {
+        for len in LENS {
+            let content = content(len);
+            let outboard = Outboard::new(&content);
+            for range in ranges(len) {
+                let chunks = chunk_range(range.clone(), len);
+                let chunks = &content[chunks.start as usize..chunks.end as usize];
+                let encoded = outboard.encode_range(chunks, range.clone());
+
+                let mut written = Vec::new();
+                outboard
+                    .write_range(chunks, range.clone(), &mut written)
+                    .await
+                    .unwrap();
+                assert_eq!(written, encoded, "len {len} range {range:?}");
+
+                let mut r = &encoded[..];
+                let decoded = decode_range(&mut r, &outboard.root(), len, range.clone())
+                    .await
+                    .unwrap();
+                assert!(r.is_empty(), "len {len} range {range:?} left bytes unread");
+                assert_eq!(decoded, chunks, "len {len} range {range:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn tampered_range_is_rejected() {
+        for len in LENS {
+            let content = content(len);
+            let outboard = Outboard::new(&content);
+            for range in ranges(len) {
+                let chunks = chunk_range(range.clone(), len);
+                let chunks = &content[chunks.start as usize..chunks.end as usize];
+                let mut encoded = outboard.encode_range(chunks, range.clone());
+                for i in [0, encoded.len() / 2, encoded.len().saturating_sub(1)] {
+                    let Some(byte) = encoded.get_mut(i) else {
+                        continue;
+                    };
+                    *byte ^= 1;
+                    let decoded =
+                        decode_range(&mut &encoded[..], &outboard.root(), len, range.clone()).await;
+                    assert!(decoded.is_err(), "len {len} range {range:?} byte {i}");
+                    encoded[i] ^= 1;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn truncated_encoding_is_rejected() {
+        let content = content(5000);
+        let (encoded, root) = encode(&content);
+        let truncated = &encoded[..encoded.len() - 1];
+        assert!(decode(&mut &truncated[..], &root, 5000).await.is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc};
+
+use clap::Parser;
+use peer2package::{
+    download::download_to_file,
+    policy::{Lockfile, Policy},
+    resources::{self, certification::Claim, PackageVersion, UserId},
+    routing::{Contact, NodeId},
+    trust::{self, TrustConfig},
+    Connection,
+};
+use quinn::{ClientConfig, Endpoint};
+
+/// Downloads a blake3 value, or the archive of a package version, from the nodes that hold it.
+///
+/// A package version is only downloaded if it satisfies the `--policy`,
+/// or if enough users trusted from `--root` have certified it.
+/// Run it again after an interruption to continue where it stopped.
+#[derive(clap::Parser)]
+struct Args {
+    #[arg(long, short = 'c')]
+    cert_path: PathBuf,
+    #[arg(long, short = 'k')]
+    key_path: PathBuf,
+    /// `host:port` of a node to find the value through
+    #[arg(long, short = 'b', required = true)]
+    bootstrap: Vec<String>,
+    /// Hex blake3 hash of the value, or `name@version` of a package
+    id: String,
+    /// File to save the value to
+    #[arg(long, short = 'o')]
+    output: PathBuf,
+    /// Hex ID of a user trusted to certify package versions and other users
+    #[arg(long = "root", value_parser = parse_user_id)]
+    roots: Vec<UserId>,
+    /// Max number of `trusted-identity` certifications between a root and a trusted user
+    #[arg(long, default_value_t = trust::DEFAULT_MAX_DEPTH)]
+    max_depth: usize,
+    /// `claim=n`, a package version needs `claim` made about it by `n` trusted users
+    #[arg(long = "require", value_parser = parse_threshold, default_value = "reviewed=1")]
+    thresholds: Vec<(Claim, usize)>,
+    /// Policy file to check package versions against, instead of `--root` and `--require`
+    #[arg(long, conflicts_with = "roots")]
+    policy: Option<PathBuf>,
+    /// Lockfile of pinned package versions
+    #[arg(long)]
+    lockfile: Option<PathBuf>,
+    /// File to write the policy decision to as JSON, for CI to report denials
+    #[arg(long)]
+    decision: Option<PathBuf>,
+}
+
+fn parse_user_id(s: &str) -> Result<UserId, &'static str> {
+    UserId::from_hex(s).ok_or("expected 64 hex characters")
+}
+
+fn parse_threshold(s: &str) -> Result<(Claim, usize), String> {
+    let (claim, n) = s.split_once('=').ok_or("expected claim=n")?;
+    let claim = Claim::from_name(claim).ok_or_else(|| format!("unknown claim {claim}"))?;
+    Ok((claim, n.parse().map_err(|e| format!("{e}"))?))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let mut keys =
+        rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(args.key_path)?))?;
+    let key = rustls::PrivateKey(keys.remove(0));
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(args.cert_path)?))?;
+    let certs: Vec<_> = certs.into_iter().map(rustls::Certificate).collect();
+
+    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    let client_config = peer2package::tls::client(key, certs)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(client_config)));
+
+    let mut seeds = Vec::new();
+    for address in args.bootstrap {
+        let id = async { Connection::dial(&endpoint, &address).await?.node_id() };
+        match id.await {
+            Ok(id) => seeds.push(Contact { id, address }),
+            Err(e) => eprintln!("could not reach bootstrap node {address} {e}"),
+        }
+    }
+
+    let id = match NodeId::from_hex(&args.id) {
+        Some(id) => id,
+        None => {
+            let (name, version) = PackageVersion::parse_spec(&args.id)?;
+            let key = PackageVersion::id_for(name, &version);
+            let release: PackageVersion = resources::find(&endpoint, key.id(), seeds.clone())
+                .await?
+                .ok_or_else(|| format!("{name}@{version} is not published"))?;
+
+            let policy = match (&args.policy, args.roots.is_empty()) {
+                (Some(path), _) => Policy::load(path)?,
+                (None, false) => Policy::from_trust(TrustConfig {
+                    roots: args.roots,
+                    max_depth: args.max_depth,
+                    thresholds: args.thresholds.into_iter().collect(),
+                }),
+                (None, true) => {
+                    return Err(
+                        "a --policy or --root is needed to decide whether to trust a package"
+                            .into(),
+                    )
+                }
+            };
+            let lockfile = match &args.lockfile {
+                Some(path) => Lockfile::load(path)?,
+                None => Lockfile::default(),
+            };
+            let (decision, verdict) = policy
+                .decide(&endpoint, seeds.clone(), &release, &lockfile)
+                .await?;
+            if let Some(path) = &args.decision {
+                std::fs::write(path, serde_json::to_vec_pretty(&decision)?)?;
+            }
+            if let Some(verdict) = verdict {
+                print!("{verdict}");
+            }
+            if decision.pinned {
+                println!("{name}@{version} is pinned in the lockfile");
+            }
+            if !decision.allowed() {
+                return Err(format!("{name}@{version} is denied {:?}", decision.denials).into());
+            }
+            NodeId::try_from(release.archive.id())?
+        }
+    };
+
+    // the value is held by the nodes closest to it, and by any providers they know of
+    let (mut holders, lookup) = peer2package::find_providers(&endpoint, id.as_id(), seeds).await;
+    for contact in lookup.closest {
+        if !holders.iter().any(|holder| holder.id == contact.id) {
+            holders.push(contact);
+        }
+    }
+    let mut sources = Vec::new();
+    for contact in &holders {
+        match Connection::dial_contact(&endpoint, contact).await {
+            Ok(connection) => sources.push(connection),
+            Err(e) => eprintln!("could not reach {} {e}", contact.id),
+        }
+    }
+    println!("downloading {id} from {} nodes", sources.len());
+
+    download_to_file(id.as_id(), sources, &args.output).await?;
+    println!("saved {} to {}", args.id, args.output.display());
+
+    endpoint.close(0u32.into(), b"done");
+    endpoint.wait_idle().await;
+    Ok(())
+}
@@ -1,15 +1,43 @@
 use std::{
-    collections::HashMap, fs::File, io::BufReader, net::SocketAddr, path::PathBuf, pin::pin,
-    sync::Arc,
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
+use bincode::Options;
 use clap::Parser;
 use peer2package::{
-    encoding::{read_message, read_message_fixed, write_message, write_message_fixed},
-    Requests,
+    bao::{self, Outboard},
+    cache::Cache,
+    encoding::{options, read_message, read_payload, write_message, write_payload},
+    hash::HashType,
+    peer_node_id,
+    providers::Providers,
+    records::{Records, MAX_TTL},
+    routing::{Contact, Insert, NodeId, RoutingTable, K},
+    store::{DiskStore, MemoryStore, Store},
+    ByteRange, Connection, Id, IdKind, Lookup, Requests, Responses, Value, QUERY_TIMEOUT,
 };
-use quinn::{Connecting, Endpoint, RecvStream, SendStream, ServerConfig};
-use tokio::sync::Notify;
+use quinn::{
+    ClientConfig, Connecting, ConnectionError, Endpoint, RecvStream, SendStream, ServerConfig,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::task::{block_in_place, JoinSet};
+
+/// How often the routing table is checked for stale contacts and idle buckets
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+/// Contacts not seen for this long are pinged, and removed if they do not respond
+const PING_AFTER: Duration = Duration::from_secs(15 * 60);
+/// Buckets with no contact seen for this long are refreshed by looking up a random ID in them
+const REFRESH_AFTER: Duration = Duration::from_secs(60 * 60);
+/// How long shutdown waits for our values to be handed off to our neighbours
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of outboards kept for serving ranges, most recently used first
+const OUTBOARD_CACHE: usize = 16;
 
 #[derive(clap::Parser)]
 struct Args {
@@ -19,6 +47,29 @@ struct Args {
     key_path: PathBuf,
     #[arg(long, short = 'a')]
     addr: SocketAddr,
+    /// Directory to store values in. Values are kept in memory if not set
+    #[arg(long, short = 's')]
+    store_path: Option<PathBuf>,
+    /// Max bytes of values to store. The least recently used are evicted first
+    #[arg(long, short = 'q', default_value_t = u64::MAX)]
+    quota: u64,
+    /// Hex key of a value that should never be evicted
+    #[arg(long, value_parser = parse_node_id)]
+    pin: Vec<NodeId>,
+    /// `host:port` of a node to join the network through
+    #[arg(long, short = 'b')]
+    bootstrap: Vec<String>,
+    /// File to save the routing table to on shutdown, and load it from on startup
+    #[arg(long, short = 'r')]
+    routing_table_path: Option<PathBuf>,
+    /// Announce stored values we are not one of the closest nodes to with provider records,
+    /// rather than copying them to the closest nodes
+    #[arg(long)]
+    announce: bool,
+}
+
+fn parse_node_id(s: &str) -> Result<NodeId, &'static str> {
+    NodeId::from_hex(s).ok_or("expected 64 hex characters")
 }
 
 #[tokio::main]
@@ -29,26 +80,610 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(File::open(args.key_path)?))?;
     let key = rustls::PrivateKey(keys.remove(0));
     let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(args.cert_path)?))?;
+    let local_id = NodeId::from_certificate(&rustls::Certificate(certs[0].clone()))?;
+    println!("node id {local_id}");
 
+    let certs: Vec<_> = certs.into_iter().map(rustls::Certificate).collect();
     let crypto_config = peer2package::tls::server(
-        key,
-        certs.into_iter().map(rustls::Certificate),
+        key.clone(),
+        certs.clone(),
         // ca_certs.into_iter().map(rustls::Certificate),
     )?;
 
     let config = ServerConfig::with_crypto(Arc::new(crypto_config));
 
-    let server = Endpoint::server(config, args.addr)?;
+    let mut server = Endpoint::server(config, args.addr)?;
+    // dial out of the server endpoint, so other nodes see the address we listen on
+    let client_config = peer2package::tls::client(key, certs)?;
+    server.set_default_client_config(ClientConfig::new(Arc::new(client_config)));
+
+    let store: Box<dyn Store> = match &args.store_path {
+        Some(path) => Box::new(DiskStore::new(path)?),
+        None => Box::new(MemoryStore::new()),
+    };
+    let store = Cache::new(store, args.quota)?;
+    for key in &args.pin {
+        store.pin(key);
+    }
+
+    // expiry times are kept next to the values, so they survive restarts together
+    let records_path = args.store_path.as_ref().map(|path| path.join("records"));
+    let mut records = match &records_path {
+        Some(path) => load_records(path)?,
+        None => Records::new(),
+    };
+    // values stored since the records were last saved, such as before a crash, have no record
+    for key in store.keys()? {
+        if records.get(&key).is_some() || store.is_pinned(&key) {
+            continue;
+        }
+        match store.get(&key)?.and_then(|value| Id::recover(&key, &value)) {
+            Some(id) => {
+                println!("recovered the record of {key}");
+                records.published(key, id.id(), MAX_TTL, false);
+            }
+            None => {
+                println!("removing {key} that is not a value stored under it");
+                store.remove(&key)?;
+            }
+        }
+    }
+
+    let mut routing = RoutingTable::new(local_id);
+    if let Some(path) = &args.routing_table_path {
+        load_routing_table(&mut routing, path)?;
+    }
 
-    let state = Arc::new(SharedState {});
-    while let Some(connecting) = server.accept().await {
-        tokio::spawn(handle_connection(state.clone(), connecting));
+    let state = Arc::new(SharedState {
+        endpoint: server.clone(),
+        routing: Mutex::new(routing),
+        store,
+        records: Mutex::new(records),
+        outboards: Mutex::new(VecDeque::new()),
+        providers: Mutex::new(Providers::new()),
+        merging: Mutex::new(()),
+        announce: args.announce,
+    });
+
+    tokio::spawn(bootstrap(state.clone(), args.bootstrap));
+    tokio::spawn(maintain_routing_table(state.clone()));
+    tokio::spawn(maintain_records(state.clone(), records_path.clone()));
+
+    let accept = async {
+        while let Some(connecting) = server.accept().await {
+            tokio::spawn(handle_connection(state.clone(), connecting));
+        }
+    };
+    tokio::select! {
+        () = accept => {}
+        () = shutdown_signal() => println!("shutting down"),
     }
 
+    if let Some(path) = &args.routing_table_path {
+        save_routing_table(&state.routing.lock().unwrap(), path)?;
+    }
+    if let Some(path) = &records_path {
+        let records = state.records.lock().unwrap();
+        save_records(&records, path)?;
+        println!("saved {} records to {}", records.len(), path.display());
+    }
+    if tokio::time::timeout(LEAVE_TIMEOUT, state.leave())
+        .await
+        .is_err()
+    {
+        eprintln!("timed out handing off values");
+    }
+    server.close(0u32.into(), b"shutdown");
+    server.wait_idle().await;
+
+    Ok(())
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+fn load_routing_table(
+    routing: &mut RoutingTable,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let snapshot = match std::fs::read(path) {
+        Ok(snapshot) => snapshot,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let contacts: Vec<Contact> = options().deserialize(&snapshot)?;
+    println!("loaded {} contacts from {}", contacts.len(), path.display());
+    for contact in contacts {
+        routing.insert(contact);
+    }
     Ok(())
 }
 
-struct SharedState {}
+fn save_routing_table(
+    routing: &RoutingTable,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let contacts: Vec<&Contact> = routing.contacts().collect();
+    std::fs::write(path, options().serialize(&contacts)?)?;
+    println!("saved {} contacts to {}", contacts.len(), path.display());
+    Ok(())
+}
+
+fn load_records(path: &Path) -> Result<Records, Box<dyn std::error::Error>> {
+    match std::fs::read(path) {
+        Ok(snapshot) => Ok(options().deserialize(&snapshot)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Records::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Saves the records next to the values, replacing the previous snapshot only once written
+fn save_records(records: &Records, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let snapshot = path.with_extension("tmp");
+    std::fs::write(&snapshot, options().serialize(records)?)?;
+    std::fs::rename(&snapshot, path)?;
+    Ok(())
+}
+
+/// Drops expired records and providers, shares the rest of the records with the nodes closest
+/// to them, and saves the records so that a crash loses little
+async fn maintain_records(state: Arc<SharedState>, records_path: Option<PathBuf>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        if let Some(path) = &records_path {
+            let saved = block_in_place(|| save_records(&state.records.lock().unwrap(), path))
+                .map_err(|e| e.to_string());
+            if let Err(e) = saved {
+                eprintln!("could not save records to {} {e}", path.display());
+            }
+        }
+
+        let expired = state.records.lock().unwrap().expired();
+        for key in expired {
+            // pinned values are republished by us, so they never expire
+            if state.store.is_pinned(&key) {
+                continue;
+            }
+            println!("expiring {key}");
+            state.records.lock().unwrap().remove(&key);
+            if let Err(e) = block_in_place(|| state.store.remove(&key)) {
+                eprintln!("could not remove expired {key} {e}");
+            }
+        }
+
+        state.providers.lock().unwrap().expire();
+
+        let due = state.records.lock().unwrap().due_for_replication();
+        for (key, _) in due {
+            state.share(key).await;
+        }
+    }
+}
+
+/// Joins the network through the seed nodes, then looks up our own ID
+/// so that the nodes closest to us learn about us and fill our buckets
+async fn bootstrap(state: Arc<SharedState>, seeds: Vec<String>) {
+    for seed in seeds {
+        let id = async { Connection::dial(&state.endpoint, &seed).await?.node_id() };
+        match id.await {
+            Ok(id) => state.observe(Contact { id, address: seed }),
+            Err(e) => eprintln!("could not reach bootstrap node {seed} {e}"),
+        }
+    }
+
+    let local_id = state.routing.lock().unwrap().local_id();
+    state.lookup(local_id).await;
+    println!(
+        "bootstrapped with {} contacts",
+        state.routing.lock().unwrap().len()
+    );
+}
+
+/// Pings contacts that have not been seen for a while, and refreshes idle buckets
+async fn maintain_routing_table(state: Arc<SharedState>) {
+    let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let stale = state.routing.lock().unwrap().stale(PING_AFTER);
+        let mut pings = JoinSet::new();
+        for contact in stale {
+            let state = state.clone();
+            pings.spawn(async move {
+                if !state.ping(&contact).await {
+                    println!("removing unresponsive {}", contact.id);
+                    state.routing.lock().unwrap().remove(&contact.id);
+                }
+            });
+        }
+        while pings.join_next().await.is_some() {}
+
+        let idle = state.routing.lock().unwrap().idle_buckets(REFRESH_AFTER);
+        for index in idle {
+            let mut random = [0; 32];
+            SystemRandom::new()
+                .fill(&mut random)
+                .expect("could not generate a random id");
+            let target = state.routing.lock().unwrap().id_in_bucket(index, random);
+            state.lookup(target).await;
+        }
+    }
+}
+
+struct SharedState {
+    endpoint: Endpoint,
+    routing: Mutex<RoutingTable>,
+    store: Cache,
+    records: Mutex<Records>,
+    /// Outboards of values recently served in ranges, as a download asks for many ranges of one value
+    outboards: Mutex<VecDeque<(NodeId, Arc<Outboard>)>>,
+    providers: Mutex<Providers>,
+    /// Held while merging a resource into the stored copy, so concurrent puts are not lost
+    merging: Mutex<()>,
+    announce: bool,
+}
+
+impl SharedState {
+    /// Records that a node is alive at this address
+    fn observe(self: &Arc<Self>, contact: Contact) {
+        let id = contact.id;
+        let mut routing = self.routing.lock().unwrap();
+        match routing.insert(contact.clone()) {
+            Insert::Inserted => {
+                println!("new contact {id}");
+                tokio::spawn(self.clone().welcome(contact));
+            }
+            Insert::Updated | Insert::Local => {}
+            Insert::Full { oldest } => {
+                tokio::spawn(self.clone().challenge(oldest, contact));
+            }
+        }
+    }
+
+    /// Pings the least recently seen contact of a full bucket.
+    /// The new contact only replaces it if it does not respond.
+    async fn challenge(self: Arc<Self>, oldest: Contact, new: Contact) {
+        if self.ping(&oldest).await {
+            println!(
+                "bucket full, ignoring {} in favour of {}",
+                new.id, oldest.id
+            );
+            return;
+        }
+        println!("evicting unresponsive {} for {}", oldest.id, new.id);
+        {
+            let mut routing = self.routing.lock().unwrap();
+            routing.remove(&oldest.id);
+            routing.insert(new.clone());
+        }
+        self.welcome(new).await;
+    }
+
+    /// Copies the values a newly seen node is now one of the k closest to,
+    /// so that lookups converging on it still find them
+    async fn welcome(self: Arc<Self>, contact: Contact) {
+        let mut values = Vec::new();
+        let mut provided = Vec::new();
+        {
+            let routing = self.routing.lock().unwrap();
+            for key in self.records.lock().unwrap().keys() {
+                if !routing.closest(&key, K).iter().any(|c| c.id == contact.id) {
+                    continue;
+                }
+                // announcing nodes only tell it where the value is, as they do the closest nodes
+                if self.announce && !is_closest(&routing, &key) {
+                    provided.push(key);
+                } else {
+                    values.push(key);
+                }
+            }
+        }
+
+        if !values.is_empty() {
+            match self.hand_off(&contact, &values).await {
+                Ok(count) => println!("handed off {count} values to {}", contact.id),
+                Err(e) => eprintln!("could not hand off values to {} {e}", contact.id),
+            }
+        }
+        if !provided.is_empty() {
+            match self.announce_to(&contact, &provided).await {
+                Ok(count) => println!("announced {count} values to {}", contact.id),
+                Err(e) => eprintln!("could not announce values to {} {e}", contact.id),
+            }
+        }
+    }
+
+    /// Announces that we provide the values under `keys` to a node over a single connection,
+    /// returning how many were announced
+    async fn announce_to(
+        self: &Arc<Self>,
+        contact: &Contact,
+        keys: &[NodeId],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let connection = self.dial(contact).await?;
+        let mut count = 0;
+        for key in keys {
+            let id = match self.records.lock().unwrap().get(key) {
+                Some(record) => record.id.clone(),
+                None => continue,
+            };
+            connection.add_provider(id.id()).await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Pushes every value we hold to the k closest nodes we know of to it, before we leave
+    async fn leave(self: &Arc<Self>) {
+        let mut neighbours: HashMap<NodeId, (Contact, Vec<NodeId>)> = HashMap::new();
+        {
+            let routing = self.routing.lock().unwrap();
+            for key in self.records.lock().unwrap().keys() {
+                for contact in routing.closest(&key, K) {
+                    let (_, keys) = neighbours
+                        .entry(contact.id)
+                        .or_insert_with(|| (contact, Vec::new()));
+                    keys.push(key);
+                }
+            }
+        }
+
+        let mut hand_offs = JoinSet::new();
+        for (contact, keys) in neighbours.into_values() {
+            let state = self.clone();
+            hand_offs.spawn(async move {
+                let result = state.hand_off(&contact, &keys).await;
+                (contact, result.map_err(|e| e.to_string()))
+            });
+        }
+        while let Some(joined) = hand_offs.join_next().await {
+            match joined {
+                Ok((contact, Ok(count))) => println!("handed off {count} values to {}", contact.id),
+                Ok((contact, Err(e))) => {
+                    eprintln!("could not hand off values to {} {e}", contact.id)
+                }
+                Err(e) => eprintln!("hand off task failed {e}"),
+            }
+        }
+    }
+
+    /// Sends the values under `keys` to a node over a single connection,
+    /// returning how many were sent
+    async fn hand_off(
+        self: &Arc<Self>,
+        contact: &Contact,
+        keys: &[NodeId],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let connection = self.dial(contact).await?;
+        let mut count = 0;
+        for key in keys {
+            let record = match self.records.lock().unwrap().get(key) {
+                Some(record) => record.clone(),
+                None => continue,
+            };
+            let Some(value) = block_in_place(|| self.store.get(key))? else {
+                continue;
+            };
+            let ttl = record.ttl();
+            if ttl.is_zero() {
+                continue;
+            }
+            connection
+                .put_value(record.id.id(), value.len(), ttl, &value[..])
+                .await?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Connects to a contact, recording it in the routing table once it has authenticated
+    async fn dial(
+        self: &Arc<Self>,
+        contact: &Contact,
+    ) -> Result<Connection, Box<dyn std::error::Error>> {
+        let connection = Connection::dial_contact(&self.endpoint, contact).await?;
+        self.observe(contact.clone());
+        Ok(connection)
+    }
+
+    /// Records a node that connected to us once we can connect back to it.
+    ///
+    /// Clients such as `fetch` connect from ports nobody can dial, so they are never recorded.
+    async fn reach_back(self: Arc<Self>, contact: Contact) {
+        match tokio::time::timeout(QUERY_TIMEOUT, self.dial(&contact)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => println!("not recording unreachable {} {e}", contact.id),
+            Err(_) => println!("not recording unreachable {} timed out", contact.id),
+        }
+    }
+
+    /// Pings a contact, recording its round trip time if it responds within [`QUERY_TIMEOUT`]
+    async fn ping(&self, contact: &Contact) -> bool {
+        let rtt = async {
+            let connection = Connection::dial_contact(&self.endpoint, contact).await?;
+            connection.ping().await
+        };
+        let rtt = match tokio::time::timeout(QUERY_TIMEOUT, rtt).await {
+            Ok(rtt) => rtt,
+            Err(_) => Err("timed out".into()),
+        };
+        match rtt {
+            Ok(rtt) => {
+                let mut routing = self.routing.lock().unwrap();
+                routing.insert(contact.clone());
+                routing.record_rtt(&contact.id, rtt);
+                true
+            }
+            Err(e) => {
+                eprintln!("{} did not respond to ping {e}", contact.id);
+                false
+            }
+        }
+    }
+
+    /// Whether we are one of the k closest nodes we know of to `key`
+    fn is_closest(&self, key: &NodeId) -> bool {
+        is_closest(&self.routing.lock().unwrap(), key)
+    }
+
+    /// Shares a record we hold with the k nodes closest to it.
+    ///
+    /// When announcing, nodes outside the k closest send a provider record instead of the value.
+    async fn share(self: &Arc<Self>, key: NodeId) {
+        if self.announce && !self.is_closest(&key) {
+            self.provide(key).await;
+        } else {
+            self.replicate(key).await;
+        }
+    }
+
+    /// Announces to the k nodes closest to a record we hold that we provide it
+    async fn provide(self: &Arc<Self>, key: NodeId) {
+        if !self.store.contains(&key).unwrap_or(false) {
+            // evicted, so there is nothing left to provide
+            self.records.lock().unwrap().remove(&key);
+            return;
+        }
+        let id = {
+            let mut records = self.records.lock().unwrap();
+            if self.store.is_pinned(&key) {
+                records.refresh(&key);
+            }
+            records.replicated(&key);
+            match records.get(&key) {
+                Some(record) => record.id.clone(),
+                None => return,
+            }
+        };
+
+        let lookup = self.lookup(key).await;
+        for contact in &lookup.closest {
+            let add = async {
+                let connection = self.dial(contact).await?;
+                connection.add_provider(id.id()).await
+            };
+            if let Err(e) = add.await {
+                eprintln!("could not announce {key} to {} {e}", contact.id);
+            }
+        }
+        println!("announced {key} to {} nodes", lookup.closest.len());
+    }
+
+    /// Sends a record we hold to the k nodes closest to it.
+    ///
+    /// Pinned records are republished as if we were their publisher, pushing back their expiry.
+    async fn replicate(self: &Arc<Self>, key: NodeId) {
+        let value = match block_in_place(|| self.store.get(&key)) {
+            Ok(Some(value)) => value,
+            Ok(None) => {
+                // evicted, so there is nothing left to replicate
+                self.records.lock().unwrap().remove(&key);
+                return;
+            }
+            Err(e) => {
+                eprintln!("could not read {key} to replicate {e}");
+                return;
+            }
+        };
+
+        let record = {
+            let mut records = self.records.lock().unwrap();
+            if self.store.is_pinned(&key) {
+                records.refresh(&key);
+            }
+            records.replicated(&key);
+            match records.get(&key) {
+                Some(record) => record.clone(),
+                None => return,
+            }
+        };
+
+        let lookup = self.lookup(key).await;
+        for contact in &lookup.closest {
+            let put = async {
+                let connection = self.dial(contact).await?;
+                let id = record.id.id();
+                connection
+                    .put_value(id, value.len(), record.ttl(), &value[..])
+                    .await
+            };
+            if let Err(e) = put.await {
+                eprintln!("could not replicate {key} to {} {e}", contact.id);
+            }
+        }
+        println!("replicated {key} to {} nodes", lookup.closest.len());
+    }
+
+    /// The outboard of a value we hold, computing it if it is not cached
+    fn outboard(&self, key: &NodeId) -> Result<Option<Arc<Outboard>>, Box<dyn std::error::Error>> {
+        if let Some(outboard) = self.cached_outboard(key) {
+            return Ok(Some(outboard));
+        }
+        let Some(value) = block_in_place(|| self.store.get(key))? else {
+            return Ok(None);
+        };
+        Ok(Some(self.outboard_of(key, &value)))
+    }
+
+    /// The outboard of `value`, which we hold under `key`, computing it if it is not cached
+    fn outboard_of(&self, key: &NodeId, value: &[u8]) -> Arc<Outboard> {
+        if let Some(outboard) = self.cached_outboard(key) {
+            return outboard;
+        }
+        let outboard = Arc::new(block_in_place(|| Outboard::new(value)));
+        let mut outboards = self.outboards.lock().unwrap();
+        outboards.push_front((*key, outboard.clone()));
+        outboards.truncate(OUTBOARD_CACHE);
+        outboard
+    }
+
+    /// The cached outboard of the value under `key`, marking it the most recently used
+    fn cached_outboard(&self, key: &NodeId) -> Option<Arc<Outboard>> {
+        let mut outboards = self.outboards.lock().unwrap();
+        let pos = outboards.iter().position(|(k, _)| k == key)?;
+        let entry = outboards.remove(pos).unwrap();
+        outboards.push_front(entry.clone());
+        Some(entry.1)
+    }
+
+    /// Runs an iterative lookup seeded from our routing table,
+    /// recording which nodes responded and which did not
+    async fn lookup(self: &Arc<Self>, target: NodeId) -> Lookup {
+        let seeds = self.routing.lock().unwrap().closest(&target, K);
+        let lookup = peer2package::lookup(&self.endpoint, target, seeds).await;
+
+        for contact in &lookup.responded {
+            self.observe(contact.clone());
+        }
+        let mut routing = self.routing.lock().unwrap();
+        for contact in &lookup.failed {
+            routing.remove(&contact.id);
+        }
+        lookup
+    }
+}
+
+/// Whether we are one of the k closest nodes in `routing` to `key`
+fn is_closest(routing: &RoutingTable, key: &NodeId) -> bool {
+    let closest = routing.closest(key, K);
+    let distance = routing.local_id().distance(key);
+    closest.len() < K || distance < closest[K - 1].id.distance(key)
+}
 
 async fn handle_connection(state: Arc<SharedState>, connecting: Connecting) {
     match handle_connection_inner(state, connecting).await {
@@ -68,14 +703,33 @@ async fn handle_connection_inner(
     let connection = connecting.await?;
     println!("connection established {:?}", connection.rtt());
 
+    let remote = Contact {
+        id: peer_node_id(&connection)?,
+        address: connection.remote_address().to_string(),
+    };
+    let known = state
+        .routing
+        .lock()
+        .unwrap()
+        .get(&remote.id)
+        .is_some_and(|contact| contact.address == remote.address);
+    if known {
+        state.observe(remote.clone());
+        state
+            .routing
+            .lock()
+            .unwrap()
+            .record_rtt(&remote.id, connection.rtt());
+    } else {
+        tokio::spawn(state.clone().reach_back(remote.clone()));
+    }
+
     loop {
         match connection.accept_bi().await {
-            Ok((send, recv)) => tokio::spawn(handle_stream(
-                state.clone(),
-                connection.stable_id(),
-                send,
-                recv,
-            )),
+            Ok((send, recv)) => {
+                tokio::spawn(handle_stream(state.clone(), remote.clone(), send, recv))
+            }
+            Err(ConnectionError::ApplicationClosed(_)) => return Ok(()),
             Err(e) => {
                 return Err(e.into());
             }
@@ -87,11 +741,11 @@ async fn handle_connection_inner(
 
 async fn handle_stream(
     state: Arc<SharedState>,
-    conn_id: usize,
+    remote: Contact,
     send: SendStream,
     recv: RecvStream,
 ) {
-    match handle_stream_inner(state, conn_id, send, recv).await {
+    match handle_stream_inner(state, remote, send, recv).await {
         Ok(()) => {}
         Err(e) => {
             eprintln!("error handling stream {e:?}");
@@ -101,7 +755,7 @@ async fn handle_stream(
 
 async fn handle_stream_inner(
     state: Arc<SharedState>,
-    conn_id: usize,
+    remote: Contact,
     send: SendStream,
     mut recv: RecvStream,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -109,242 +763,197 @@ async fn handle_stream_inner(
 
     let message = read_message::<Requests>(&mut recv).await?;
 
-    // match message.get() {
-    //     Requests::Publish(publish) => handle_stream_publish(state, send, publish).await?,
-    //     Requests::Consume(consume) => {
-    //         handle_stream_consume(state, conn_id, send, recv, consume).await?
-    //     }
-    // }
+    match message.get() {
+        Requests::FindNode(id) => handle_stream_find_node(state, remote.id, send, *id).await?,
+        Requests::FindValue(id) => handle_stream_find_value(state, remote.id, send, *id).await?,
+        Requests::PutValue(value) => handle_stream_put_value(state, send, recv, *value).await?,
+        Requests::Ping => handle_stream_ping(send).await?,
+        Requests::GetRange(range) => handle_stream_get_range(state, send, *range).await?,
+        Requests::AddProvider(id) => handle_stream_add_provider(state, remote, send, *id).await?,
+        Requests::GetProviders(id) => handle_stream_get_providers(state, send, *id).await?,
+    }
 
     Ok(())
 }
 
-// async fn handle_stream_publish(
-//     state: Arc<SharedState>,
-//     mut send: SendStream,
-//     publish: &Requests<'_>,
-// ) -> Result<(), Box<dyn std::error::Error>> {
-//     let Publish {
-//         exchange,
-//         routing_key,
-//         message,
-//     } = *publish;
-//     let message_str = String::from_utf8_lossy(message);
-
-//     println!("{exchange} {routing_key} {message_str}");
-
-//     let message_id = uuid::Uuid::new_v4();
-//     state.messages.insert(message_id.as_bytes(), message)?;
-
-//     let exchange = state.exchanges.get(exchange).ok_or("unknown exchange")?;
-//     let queues = exchange.routes.get(routing_key).ok_or("unknown route")?;
-//     for queue in queues {
-//         let mut key = Vec::new();
-//         key.extend_from_slice(queue.as_bytes());
-//         key.extend_from_slice(&[0]);
-//         key.extend_from_slice(message_id.as_bytes());
-
-//         dbg!("inserting queue");
-//         dbg!(state.available.insert(key, &(0_u64).to_le_bytes()))?;
-//     }
-
-//     dbg!("flushing");
-//     state.messages.flush_async().await?;
-//     state.available.flush_async().await?;
-
-//     for queue in queues {
-//         let queue = state.queues.get(queue).unwrap();
-//         queue.notify.notify_one();
-//     }
-
-//     for entry in state.available.iter() {
-//         let (key, _) = entry?;
-//         println!("{}", String::from_utf8_lossy(&key));
-//     }
-
-//     write_message_fixed(&PublishConfirm::Ack, &mut send, &mut [0; 1]).await?;
-//     send.finish().await?;
-
-//     Ok(())
-// }
-
-// async fn handle_stream_consume(
-//     state: Arc<SharedState>,
-//     conn_id: usize,
-//     mut send: SendStream,
-//     mut recv: RecvStream,
-//     consume: &Consume<'_>,
-// ) -> Result<(), Box<dyn std::error::Error>> {
-//     let queue_name = consume.queue;
-//     dbg!(&queue_name);
-
-//     let mut key_prefix = Vec::new();
-//     key_prefix.extend_from_slice(queue_name.as_bytes());
-//     key_prefix.extend_from_slice(&[0]);
-
-//     // use sled::transaction::Transactional;
-//     // let res = (&state.published, &state.acquired).transaction(|(message, acquired)| {
-//     //     if let Some(entry) = state.published.range((&*key)..).next() {
-//     //         let (key2, value) = entry?;
-//     //         if let Some(id) = key2.strip_prefix(&*key) {
-//     //             let message_id = uuid::Uuid::from_bytes(id.try_into().unwrap());
-//     //             dbg!(message_id);
-
-//     //             let message = message.remove(&key2)?.unwrap();
-//     //             acquired.insert(&key2, message)?;
-//     //             Ok((message_id, value))
-//     //         } else {
-//     //             Err(ConflictableTransactionError::Abort(()))
-//     //         }
-//     //     } else {
-//     //         Err(ConflictableTransactionError::Abort(()))
-//     //     }
-//     // });
-
-//     // match res {
-//     //     Ok((message_id, value)) => {
-//     //         println!("{message_id} {}", String::from_utf8_lossy(&value));
-//     //     }
-//     //     Err(TransactionError::Abort(())) => return Err("abort".into()),
-//     //     Err(TransactionError::Storage(e)) => return Err(e.into()),
-//     // }
-
-//     let queue = state.queues.get(queue_name).unwrap();
-//     let (key, payload) = loop {
-//         let mut notified = pin!(queue.notify.notified());
-//         notified.as_mut().enable();
-
-//         dbg!("checking for messages");
-
-//         let message = if let Some(entry) = state.available.scan_prefix(&*key_prefix).next() {
-//             let (key, _) = entry?;
-//             let message_id = key.strip_prefix(&*key_prefix).unwrap().try_into().unwrap();
-//             let message_id = uuid::Uuid::from_bytes(message_id);
-//             dbg!(message_id);
-
-//             let mut conn_message_id = Vec::new();
-//             conn_message_id.extend_from_slice(&u64::to_ne_bytes(conn_id as u64));
-//             conn_message_id.extend_from_slice(&key);
-
-//             // move message from published to acquired
-//             let res = (&state.available, &state.acquired, &state.connections).transaction(
-//                 |(published, acquired, connections): &(
-//                     TransactionalTree,
-//                     TransactionalTree,
-//                     TransactionalTree,
-//                 )| {
-//                     dbg!("swap");
-//                     // remove from the available queue
-//                     let val = published
-//                         .remove(&key)?
-//                         .ok_or(ConflictableTransactionError::Abort(()))?;
-//                     // add to the acquired queue
-//                     acquired.insert(&key, val)?;
-//                     // add to the acquired list of this connection
-//                     connections.insert(&*conn_message_id, &[])?;
-//                     Ok(())
-//                 },
-//             );
-//             match res {
-//                 Ok(()) => state
-//                     .messages
-//                     .get(message_id.as_bytes())?
-//                     .map(|payload| (key, payload)),
-//                 Err(TransactionError::Abort(())) => continue,
-//                 Err(TransactionError::Storage(e)) => return Err(e.into()),
-//             }
-//         } else {
-//             dbg!("none with prefix :(");
-//             None
-//         };
-
-//         // for entry in state.published.iter() {
-//         //     let (key, _) = entry?;
-//         //     println!("entry {}", String::from_utf8_lossy(&key));
-//         // }
-//         // for entry in state.acquired.iter() {
-//         //     let (key, _) = entry?;
-//         //     println!("acquired {}", String::from_utf8_lossy(&key));
-//         // }
-
-//         // let mut conn_state = conn_state.messages.lock().await;
-//         // let mut message_lock = queue.messages.lock().await;
-
-//         // let message = message_lock.iter_mut().find(|m| !m.inflight);
-
-//         match message {
-//             Some(message) => {
-//                 // conn_state.insert((queue_name.to_owned(), message.id));
-//                 // dbg!(&conn_state);
-//                 // message.inflight = true;
-
-//                 break message;
-//             }
-//             None => {
-//                 dbg!("waiting");
-//                 // drop(message_lock);
-//                 notified.await
-//             }
-//         };
-//     };
-
-//     write_message(&*payload, &mut send).await?;
-//     send.finish().await?;
-
-//     let confirm: MessageAck = read_message_fixed(&mut recv, &mut [0; 1]).await?;
-
-//     // let mut conn_state = conn_state.messages.lock().await;
-//     // let mut messages = queue.messages.lock().await;
-//     // let (idx, message) = messages
-//     //     .iter_mut()
-//     //     .enumerate()
-//     //     .find(|m| m.1.id == message_id)
-//     //     .ok_or("invalid message")?;
-
-//     // match dbg!(confirm) {
-//     //     MessageAck::Ack => {
-//     //         messages.remove(idx);
-//     //     }
-//     //     MessageAck::Nack => message.inflight = false,
-//     //     // DLQ
-//     //     MessageAck::Reject => {
-//     //         messages.remove(idx);
-//     //     }
-//     // }
-
-//     let mut conn_message_id = Vec::new();
-//     conn_message_id.extend_from_slice(&usize::to_ne_bytes(conn_id));
-//     conn_message_id.extend_from_slice(&key);
-
-//     // move message from acquired to available
-//     let res = (&state.available, &state.acquired, &state.connections).transaction(
-//         |(published, acquired, connections): &(
-//             TransactionalTree,
-//             TransactionalTree,
-//             TransactionalTree,
-//         )| {
-//             dbg!("release");
-//             connections.remove(&key)?.unwrap();
-//             acquired.remove(&key)?.unwrap();
-//             match confirm {
-//                 MessageAck::Ack => {}
-//                 MessageAck::Nack => {
-//                     published.insert(&key, &[])?;
-//                 }
-//                 // TODO: DLQ
-//                 MessageAck::Reject => {}
-//             }
-//             Ok(())
-//         },
-//     );
-//     match res {
-//         Ok(()) => {}
-//         Err(TransactionError::Abort(())) => return Err("aborted".into()),
-//         Err(TransactionError::Storage(e)) => return Err(e.into()),
-//     }
-
-//     // conn_state.remove(&(queue_name.to_owned(), message_id));
-//     // dbg!(&conn_state);
-
-//     Ok(())
-// }
+async fn handle_stream_ping(mut send: SendStream) -> Result<(), Box<dyn std::error::Error>> {
+    write_message(&Responses::Pong, &mut send).await?;
+    send.finish().await?;
+    Ok(())
+}
+
+async fn handle_stream_add_provider(
+    state: Arc<SharedState>,
+    remote: Contact,
+    mut send: SendStream,
+    id: Id<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = NodeId::for_id(id);
+    // only the nodes closest to a value are asked for its providers
+    if !state.is_closest(&key) {
+        return Err(format!("not one of the closest nodes to {key}").into());
+    }
+    if !state.providers.lock().unwrap().add(key, remote.clone()) {
+        return Err(format!("too many values with providers to add {key}").into());
+    }
+    println!("{} provides {key}", remote.id);
+
+    write_message(&Responses::Location(remote.location()), &mut send).await?;
+    send.finish().await?;
+
+    Ok(())
+}
+
+async fn handle_stream_get_providers(
+    state: Arc<SharedState>,
+    mut send: SendStream,
+    id: Id<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let providers = state.providers.lock().unwrap().get(&NodeId::for_id(id));
+
+    for provider in &providers {
+        write_message(&Responses::Location(provider.location()), &mut send).await?;
+    }
+    send.finish().await?;
+
+    Ok(())
+}
+
+async fn handle_stream_find_node(
+    state: Arc<SharedState>,
+    remote_id: NodeId,
+    mut send: SendStream,
+    id: Id<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let target = NodeId::for_id(id);
+    let closest = state.routing.lock().unwrap().closest(&target, K + 1);
+
+    for contact in closest.iter().filter(|c| c.id != remote_id).take(K) {
+        write_message(&Responses::Location(contact.location()), &mut send).await?;
+    }
+    send.finish().await?;
+
+    Ok(())
+}
+
+async fn handle_stream_find_value(
+    state: Arc<SharedState>,
+    remote_id: NodeId,
+    mut send: SendStream,
+    id: Id<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let key = NodeId::for_id(id);
+    let value = block_in_place(|| state.store.get(&key))?;
+
+    match value {
+        Some(value) => {
+            let ttl = match state.records.lock().unwrap().get(&key) {
+                Some(record) => record.ttl(),
+                None => MAX_TTL,
+            };
+            let header = Value {
+                id,
+                value_len: value.len(),
+                ttl: ttl.as_secs(),
+            };
+            write_message(&Responses::Value(header), &mut send).await?;
+            // blake3 values are encoded from their cached outboard, rather than rehashed
+            if matches!(id.kind(), Ok(IdKind::Content(HashType::Blake3))) {
+                let outboard = state.outboard_of(&key, &value);
+                outboard
+                    .write_range(&value, 0..value.len() as u64, &mut send)
+                    .await?;
+            } else {
+                write_payload(id, &value, &mut send).await?;
+            }
+            send.finish().await?;
+            Ok(())
+        }
+        None => handle_stream_find_node(state, remote_id, send, id).await,
+    }
+}
+
+async fn handle_stream_get_range(
+    state: Arc<SharedState>,
+    mut send: SendStream,
+    range: ByteRange<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if range.id.algorithm()? != HashType::Blake3 {
+        return Err("ranges can only be verified for blake3 values".into());
+    }
+    let key = NodeId::for_id(range.id);
+    let Some(outboard) = state.outboard(&key)? else {
+        send.finish().await?;
+        return Ok(());
+    };
+
+    let content_len = outboard.content_len();
+    let range_bytes = range.start.min(content_len)..range.end.min(content_len);
+    let chunks = bao::chunk_range(range_bytes.clone(), content_len);
+    let content = block_in_place(|| state.store.get_range(&key, chunks))?
+        .ok_or("value was removed while serving it")?;
+
+    let ttl = match state.records.lock().unwrap().get(&key) {
+        Some(record) => record.ttl(),
+        None => MAX_TTL,
+    };
+    let header = Value {
+        id: range.id,
+        value_len: content_len as usize,
+        ttl: ttl.as_secs(),
+    };
+    write_message(&Responses::Value(header), &mut send).await?;
+    send.write_all(&outboard.encode_range(&content, range_bytes))
+        .await?;
+    send.finish().await?;
+
+    Ok(())
+}
+
+async fn handle_stream_put_value(
+    state: Arc<SharedState>,
+    mut send: SendStream,
+    mut recv: RecvStream,
+    value: Value<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut payload = read_payload(value.id, value.value_len, &mut recv).await?;
+
+    let key = NodeId::for_id(value.id);
+    let evictions = state.store.stats().evictions;
+    // resources like certifications accumulate from many publishers, rather than being replaced
+    let mut changed = false;
+    if let IdKind::Resource(resource_type) = value.id.kind()? {
+        let _merging = state.merging.lock().unwrap();
+        block_in_place(|| -> Result<(), Box<dyn std::error::Error>> {
+            if let Some(stored) = state.store.get(&key)? {
+                payload = resource_type.merge(&stored, payload)?;
+                changed = payload != stored;
+            }
+            Ok(state.store.put(&key, &payload)?)
+        })?;
+    } else {
+        block_in_place(|| state.store.put(&key, &payload))?;
+    }
+    let stats = state.store.stats();
+    if stats.evictions > evictions {
+        println!("evicted values to make room for {key} {stats:?}");
+    }
+
+    let ttl = Duration::from_secs(value.ttl);
+    let new = state
+        .records
+        .lock()
+        .unwrap()
+        .published(key, value.id, ttl, changed);
+    // nodes outside the k closest, such as an office cache, pass new values on
+    // straight away rather than waiting for the next replication round
+    if new && !state.is_closest(&key) {
+        let state = state.clone();
+        tokio::spawn(async move { state.share(key).await });
+    }
+
+    write_message(&Responses::Value(value), &mut send).await?;
+    send.finish().await?;
+
+    Ok(())
+}
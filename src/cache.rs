@@ -0,0 +1,292 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io,
+    ops::Range,
+    sync::Mutex,
+};
+
+use crate::{routing::NodeId, store::Store};
+
+/// A [`Store`] bounded by a byte quota.
+///
+/// When a new value does not fit, the least recently used values are evicted
+/// until it does. Pinned values are never evicted.
+pub struct Cache {
+    inner: Box<dyn Store>,
+    quota: u64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    /// Bytes currently stored
+    pub used: u64,
+    /// Bytes currently stored in pinned values
+    pub pinned: u64,
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of values evicted to make room for others
+    pub evictions: u64,
+    pub evicted_bytes: u64,
+    /// Number of puts rejected because they could never fit in the quota
+    pub rejections: u64,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Size and last use of every stored value
+    entries: HashMap<NodeId, (u64, u64)>,
+    /// Unpinned values by last use, least recent first
+    order: BTreeMap<u64, NodeId>,
+    pins: HashSet<NodeId>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl Cache {
+    /// Wraps `inner`, taking account of the values it already holds
+    pub fn new(inner: Box<dyn Store>, quota: u64) -> io::Result<Self> {
+        let mut state = CacheState::default();
+        for key in inner.keys()? {
+            if let Some(size) = inner.size(&key)? {
+                state.clock += 1;
+                state.entries.insert(key, (size, state.clock));
+                state.order.insert(state.clock, key);
+                state.stats.used += size;
+            }
+        }
+
+        Ok(Self {
+            inner,
+            quota,
+            state: Mutex::new(state),
+        })
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Exempts `key` from eviction. It need not be stored yet.
+    pub fn pin(&self, key: &NodeId) {
+        let mut state = self.state.lock().unwrap();
+        if !state.pins.insert(*key) {
+            return;
+        }
+        if let Some(&(size, last_used)) = state.entries.get(key) {
+            state.order.remove(&last_used);
+            state.stats.pinned += size;
+        }
+    }
+
+    pub fn unpin(&self, key: &NodeId) {
+        let mut state = self.state.lock().unwrap();
+        if !state.pins.remove(key) {
+            return;
+        }
+        if let Some(&(size, last_used)) = state.entries.get(key) {
+            state.order.insert(last_used, *key);
+            state.stats.pinned -= size;
+        }
+    }
+
+    pub fn is_pinned(&self, key: &NodeId) -> bool {
+        self.state.lock().unwrap().pins.contains(key)
+    }
+}
+
+impl CacheState {
+    /// Records a read of `key`, which found a value if `hit`
+    fn used(&mut self, key: &NodeId, hit: bool) {
+        if hit {
+            self.stats.hits += 1;
+            self.touch(key);
+        } else {
+            self.stats.misses += 1;
+        }
+    }
+
+    fn touch(&mut self, key: &NodeId) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some((_, last_used)) = self.entries.get_mut(key) {
+            if self.order.remove(last_used).is_some() {
+                self.order.insert(clock, *key);
+            }
+            *last_used = clock;
+        }
+    }
+
+    fn forget(&mut self, key: &NodeId) -> Option<u64> {
+        let (size, last_used) = self.entries.remove(key)?;
+        self.order.remove(&last_used);
+        self.stats.used -= size;
+        if self.pins.contains(key) {
+            self.stats.pinned -= size;
+        }
+        Some(size)
+    }
+}
+
+impl Store for Cache {
+    fn get(&self, key: &NodeId) -> io::Result<Option<Vec<u8>>> {
+        let value = self.inner.get(key)?;
+        self.state.lock().unwrap().used(key, value.is_some());
+        Ok(value)
+    }
+
+    fn get_range(&self, key: &NodeId, range: Range<u64>) -> io::Result<Option<Vec<u8>>> {
+        let bytes = self.inner.get_range(key, range)?;
+        self.state.lock().unwrap().used(key, bytes.is_some());
+        Ok(bytes)
+    }
+
+    fn put(&self, key: &NodeId, value: &[u8]) -> io::Result<()> {
+        let size = value.len() as u64;
+        let mut state = self.state.lock().unwrap();
+
+        // pinned values are never evicted, so they are the least we could shrink to
+        let mut pinned = state.stats.pinned;
+        if state.pins.contains(key) {
+            pinned -= state.entries.get(key).map_or(0, |&(size, _)| size);
+        }
+        if pinned + size > self.quota {
+            state.stats.rejections += 1;
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "value does not fit in the cache quota",
+            ));
+        }
+
+        state.forget(key);
+        while state.stats.used + size > self.quota {
+            let (_, oldest) = state.order.pop_first().unwrap();
+            self.inner.remove(&oldest)?;
+            let evicted = state.forget(&oldest).unwrap();
+            state.stats.evictions += 1;
+            state.stats.evicted_bytes += evicted;
+        }
+
+        self.inner.put(key, value)?;
+        state.clock += 1;
+        let clock = state.clock;
+        state.entries.insert(*key, (size, clock));
+        state.stats.used += size;
+        if state.pins.contains(key) {
+            state.stats.pinned += size;
+        } else {
+            state.order.insert(clock, *key);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &NodeId) -> io::Result<()> {
+        self.inner.remove(key)?;
+        self.state.lock().unwrap().forget(key);
+        Ok(())
+    }
+
+    fn contains(&self, key: &NodeId) -> io::Result<bool> {
+        Ok(self.state.lock().unwrap().entries.contains_key(key))
+    }
+
+    fn size(&self, key: &NodeId) -> io::Result<Option<u64>> {
+        let state = self.state.lock().unwrap();
+        Ok(state.entries.get(key).map(|&(size, _)| size))
+    }
+
+    fn keys(&self) -> io::Result<Vec<NodeId>> {
+        Ok(self.state.lock().unwrap().entries.keys().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn key(i: u8) -> NodeId {
+        NodeId([i; 32])
+    }
+
+    fn cache(quota: u64) -> Cache {
+        Cache::new(Box::new(MemoryStore::new()), quota).unwrap()
+    }
+
+    #[test]
+    fn accounts_for_puts_replacements_and_removals() {
+        let cache = cache(100);
+        cache.put(&key(1), &[0; 10]).unwrap();
+        cache.put(&key(2), &[0; 20]).unwrap();
+        assert_eq!(cache.stats().used, 30);
+
+        cache.put(&key(1), &[0; 5]).unwrap();
+        assert_eq!(cache.stats().used, 25);
+        assert_eq!(cache.size(&key(1)).unwrap(), Some(5));
+
+        cache.remove(&key(2)).unwrap();
+        assert_eq!(cache.stats().used, 5);
+        assert!(!cache.contains(&key(2)).unwrap());
+
+        assert!(cache.get(&key(1)).unwrap().is_some());
+        assert!(cache.get(&key(2)).unwrap().is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let cache = cache(30);
+        cache.put(&key(1), &[0; 10]).unwrap();
+        cache.put(&key(2), &[0; 10]).unwrap();
+        cache.put(&key(3), &[0; 10]).unwrap();
+        // reading 1 makes 2 the least recently used
+        cache.get(&key(1)).unwrap();
+
+        cache.put(&key(4), &[0; 10]).unwrap();
+        assert!(!cache.contains(&key(2)).unwrap());
+        assert!(cache.contains(&key(1)).unwrap());
+
+        cache.put(&key(5), &[0; 20]).unwrap();
+        assert!(!cache.contains(&key(3)).unwrap());
+        assert!(!cache.contains(&key(1)).unwrap());
+        assert!(cache.contains(&key(4)).unwrap());
+
+        let stats = cache.stats();
+        assert_eq!(stats.used, 30);
+        assert_eq!((stats.evictions, stats.evicted_bytes), (3, 30));
+    }
+
+    #[test]
+    fn never_evicts_pinned_values() {
+        let cache = cache(30);
+        cache.pin(&key(1));
+        cache.put(&key(1), &[0; 10]).unwrap();
+        cache.put(&key(2), &[0; 10]).unwrap();
+        cache.put(&key(3), &[0; 20]).unwrap();
+        assert!(cache.contains(&key(1)).unwrap());
+        assert!(!cache.contains(&key(2)).unwrap());
+        assert_eq!(cache.stats().pinned, 10);
+
+        // only 20 bytes are not pinned
+        assert!(cache.put(&key(4), &[0; 25]).is_err());
+        assert_eq!(cache.stats().rejections, 1);
+        assert!(cache.contains(&key(3)).unwrap());
+
+        cache.unpin(&key(1));
+        assert_eq!(cache.stats().pinned, 0);
+        cache.put(&key(4), &[0; 25]).unwrap();
+        assert!(!cache.contains(&key(1)).unwrap());
+        assert_eq!(cache.stats().used, 25);
+    }
+
+    #[test]
+    fn counts_values_already_in_the_store() {
+        let store = MemoryStore::new();
+        store.put(&key(1), &[0; 10]).unwrap();
+        store.put(&key(2), &[0; 7]).unwrap();
+        let cache = Cache::new(Box::new(store), 100).unwrap();
+        assert_eq!(cache.stats().used, 17);
+        assert_eq!(cache.keys().unwrap().len(), 2);
+    }
+}
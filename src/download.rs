@@ -0,0 +1,316 @@
+//! Downloads a value in ranges from several nodes that hold it at once.
+//!
+//! Each source is given pieces sized to its observed throughput, so faster sources
+//! end up serving more of the value. A piece that fails, including failing verification,
+//! goes back to be requested from another source, and the source that failed it is dropped.
+//!
+//! The length a source claims for the value is only trusted once it has sent the chunk holding
+//! the last byte, since verifying any other range does not pin down the length.
+//!
+//! [`download_to_file`] keeps verified pieces in a partial file as they arrive,
+//! so an interrupted download continues from where it stopped when it is run again.
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fs::{self, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bincode::Options;
+use blake3::guts::CHUNK_LEN;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+
+use crate::{encoding::options, Connection, Id, OwnedId};
+
+/// Smallest piece requested from a source, and the size of the first piece
+/// which is requested before the length of the value is known
+pub const MIN_PIECE: u64 = 64 * 1024;
+pub const MAX_PIECE: u64 = 16 * 1024 * 1024;
+/// Pieces are sized so that each takes about this long from its source
+pub const PIECE_TIME: Duration = Duration::from_secs(1);
+
+struct Source {
+    connection: Connection,
+    /// Bytes per second, averaged over the pieces it has served
+    throughput: Option<f64>,
+    busy: bool,
+    failed: bool,
+}
+
+impl Source {
+    fn piece_len(&self) -> u64 {
+        let len = match self.throughput {
+            Some(throughput) => (throughput * PIECE_TIME.as_secs_f64()) as u64,
+            None => MIN_PIECE,
+        };
+        let chunk = CHUNK_LEN as u64;
+        len.clamp(MIN_PIECE, MAX_PIECE) / chunk * chunk
+    }
+
+    fn served(&mut self, bytes: usize, elapsed: Duration) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        self.throughput = Some(match self.throughput {
+            Some(throughput) => throughput * 0.7 + sample * 0.3,
+            None => sample,
+        });
+    }
+}
+
+/// Downloads the blake3 value `id` from every source at once, verifying each piece as it arrives
+pub async fn download(
+    id: Id<'_>,
+    sources: impl IntoIterator<Item = Connection>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut content = Vec::new();
+    fetch(id, sources, None, VecDeque::new(), |_, start, bytes| {
+        // grown as pieces arrive, rather than up front to the length
+        let start = start as usize;
+        let end = start + bytes.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[start..end].copy_from_slice(bytes);
+        Ok(())
+    })
+    .await?;
+    Ok(content)
+}
+
+/// Which parts of a value a partial file holds
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    id: OwnedId,
+    len: Option<u64>,
+    /// Verified ranges, sorted and merged
+    done: Vec<Range<u64>>,
+}
+
+impl Progress {
+    fn insert(&mut self, range: Range<u64>) {
+        self.done.push(range);
+        self.done.sort_by_key(|range| range.start);
+        let mut merged: Vec<Range<u64>> = Vec::with_capacity(self.done.len());
+        for range in self.done.drain(..) {
+            match merged.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        self.done = merged;
+    }
+
+    /// The ranges still to download, if the length is known
+    fn missing(&self) -> VecDeque<Range<u64>> {
+        let Some(len) = self.len else {
+            return VecDeque::new();
+        };
+        let mut missing = VecDeque::new();
+        let mut start = 0;
+        for range in &self.done {
+            if range.start > start {
+                missing.push_back(start..range.start);
+            }
+            start = range.end;
+        }
+        if start < len {
+            missing.push_back(start..len);
+        }
+        missing
+    }
+
+    fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        // write then rename, so an interrupted save never loses the last progress
+        let tmp = with_suffix(path, ".tmp");
+        fs::write(&tmp, options().serialize(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Downloads the blake3 value `id` to `path`, like [`download`].
+///
+/// Verified pieces are kept in `<path>.part`, and the ranges they cover in `<path>.progress`.
+/// If those are left by an interrupted download of the same value, it continues from them.
+pub async fn download_to_file(
+    id: Id<'_>,
+    sources: impl IntoIterator<Item = Connection>,
+    path: &Path,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let part_path = with_suffix(path, ".part");
+    let progress_path = with_suffix(path, ".progress");
+
+    let saved = match fs::read(&progress_path) {
+        Ok(saved) => options().deserialize::<Progress>(&saved).ok(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut progress = match saved {
+        Some(progress) if progress.id.id() == id && part_path.exists() => {
+            let verified: u64 = progress.done.iter().map(|r| r.end - r.start).sum();
+            println!("resuming download with {verified} bytes already verified");
+            progress
+        }
+        _ => Progress {
+            id: id.into(),
+            len: None,
+            done: Vec::new(),
+        },
+    };
+
+    let mut part = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(progress.len.is_none())
+        .open(&part_path)?;
+    let len = progress.len;
+    let missing = progress.missing();
+    fetch(id, sources, len, missing, |len, start, bytes| {
+        part.seek(SeekFrom::Start(start))?;
+        part.write_all(bytes)?;
+        // the piece must be on disk before the progress says it is
+        part.sync_data()?;
+        progress.len = Some(len);
+        progress.insert(start..start + bytes.len() as u64);
+        progress.save(&progress_path)
+    })
+    .await?;
+
+    part.set_len(progress.len.unwrap_or(0))?;
+    drop(part);
+    fs::rename(&part_path, path)?;
+    fs::remove_file(&progress_path)?;
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = OsString::from(path);
+    path.push(suffix);
+    path.into()
+}
+
+/// Fetches the `missing` ranges of a value from `sources`, passing each verified piece
+/// to `write` with the length of the value and the offset of the piece.
+///
+/// If `len` is not known yet, it is learned from the first piece once its source has proven it,
+/// and `missing` is ignored.
+async fn fetch(
+    id: Id<'_>,
+    sources: impl IntoIterator<Item = Connection>,
+    mut len: Option<u64>,
+    mut missing: VecDeque<Range<u64>>,
+    mut write: impl FnMut(u64, u64, &[u8]) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = Arc::new(OwnedId::from(id));
+    let mut sources: Vec<_> = sources
+        .into_iter()
+        .map(|connection| Source {
+            connection,
+            throughput: None,
+            busy: false,
+            failed: false,
+        })
+        .collect();
+
+    // the rest of the value is queued once the first piece tells us its length
+    if len.is_none() {
+        missing.clear();
+        missing.push_back(0..MIN_PIECE);
+    }
+    let mut pieces = JoinSet::new();
+
+    loop {
+        for (index, source) in sources.iter_mut().enumerate() {
+            if source.busy || source.failed {
+                continue;
+            }
+            let Some(range) = missing.pop_front() else {
+                break;
+            };
+            let piece = range.start..range.end.min(range.start + source.piece_len());
+            if piece.end < range.end {
+                missing.push_front(piece.end..range.end);
+            }
+
+            source.busy = true;
+            let connection = source.connection.clone();
+            let id = id.clone();
+            let prove_len = len.is_none();
+            pieces.spawn(async move {
+                let started = Instant::now();
+                let result = async {
+                    let (value_len, bytes) = connection
+                        .get_range(id.id(), piece.clone())
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    if prove_len && value_len > piece.end {
+                        prove_len_of(&connection, id.id(), value_len).await?;
+                    }
+                    Ok((value_len, bytes))
+                };
+                let result = result.await;
+                (index, piece, started.elapsed(), result)
+            });
+        }
+
+        let Some(joined) = pieces.join_next().await else {
+            break;
+        };
+        let (index, piece, elapsed, result) = joined?;
+        let source = &mut sources[index];
+        source.busy = false;
+
+        let result = result.and_then(|(value_len, bytes)| {
+            let expected = piece.start.min(value_len)..piece.end.min(value_len);
+            if bytes.len() as u64 != expected.end - expected.start {
+                return Err(format!("sent {} bytes for {expected:?}", bytes.len()));
+            }
+            // `len` is only ever a proven length, so a source disagreeing with it is wrong
+            match len {
+                Some(len) if len != value_len => {
+                    Err(format!("claims a length of {value_len} not {len}"))
+                }
+                _ => Ok((value_len, bytes)),
+            }
+        });
+        match result {
+            Ok((value_len, bytes)) => {
+                source.served(bytes.len(), elapsed);
+                if len.is_none() && value_len > piece.end {
+                    missing.push_back(piece.end..value_len);
+                }
+                len = Some(value_len);
+                write(value_len, piece.start, &bytes)?;
+            }
+            Err(e) => {
+                let address = source.connection.remote_address();
+                eprintln!("download of {piece:?} from {address} failed {e}");
+                source.failed = true;
+                missing.push_front(piece);
+            }
+        }
+    }
+
+    if len.is_none() || !missing.is_empty() {
+        return Err("no sources left to download from".into());
+    }
+    Ok(())
+}
+
+/// Checks the length a source claims for a value by fetching the chunk holding its last byte
+async fn prove_len_of(connection: &Connection, id: Id<'_>, len: u64) -> Result<(), String> {
+    let chunk = CHUNK_LEN as u64;
+    let last = (len - 1) / chunk * chunk..len;
+    let (proven, _) = connection
+        .get_range(id, last)
+        .await
+        .map_err(|e| e.to_string())?;
+    if proven != len {
+        return Err(format!("claims a length of {len} then {proven}"));
+    }
+    Ok(())
+}
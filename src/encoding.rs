@@ -3,6 +3,31 @@ use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use yoke::{Yoke, Yokeable};
 
+use crate::{bao, hash::HashType, Id, IdKind};
+
+/// Largest value a node reads from a peer, so a peer cannot make it buffer without bound
+pub const MAX_VALUE_LEN: usize = 1 << 30;
+
+/// Largest framed message a node reads
+pub const MAX_MESSAGE_LEN: u64 = 1 << 20;
+
+/// Lowercase hex of `bytes`
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Parses the hex of exactly `N` bytes
+pub fn from_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
 pub fn options() -> impl Options {
     bincode::DefaultOptions::new()
         .with_little_endian()
@@ -37,20 +62,41 @@ pub async fn write_message_fixed(
 pub async fn read_message<T>(
     r: &mut (impl AsyncRead + Unpin),
 ) -> Result<Yoke<T, Vec<u8>>, Box<dyn std::error::Error>>
+where
+    T: for<'a> Yokeable<'a>,
+    for<'de> <T as yoke::Yokeable<'de>>::Output: Deserialize<'de>,
+{
+    Ok(try_read_message(r)
+        .await?
+        .ok_or("stream finished before message")?)
+}
+
+/// Reads a framed message and zero-copy deserialises it.
+/// Returns `None` if the stream finished cleanly before the next message.
+pub async fn try_read_message<T>(
+    r: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<Yoke<T, Vec<u8>>>, Box<dyn std::error::Error>>
 where
     T: for<'a> Yokeable<'a>,
     for<'de> <T as yoke::Yokeable<'de>>::Output: Deserialize<'de>,
 {
     let mut payload_len = [0; 8];
-    r.read_exact(&mut payload_len).await?;
-    let payload_len = dbg!(u64::from_le_bytes(payload_len));
+    let n = r.read(&mut payload_len).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    r.read_exact(&mut payload_len[n..]).await?;
+    let payload_len = u64::from_le_bytes(payload_len);
+    if payload_len > MAX_MESSAGE_LEN {
+        return Err(format!("message of {payload_len} bytes is too large").into());
+    }
 
     let mut payload = vec![0; payload_len as usize];
     r.read_exact(&mut payload).await?;
 
-    Ok(Yoke::try_attach_to_cart(payload, |bytes| {
+    Ok(Some(Yoke::try_attach_to_cart(payload, |bytes| {
         options().deserialize(bytes)
-    })?)
+    })?))
 }
 
 /// Reads an unframed message of a fixed known size into the buffer and deserialises it
@@ -61,3 +107,49 @@ pub async fn read_message_fixed<'de, T: Deserialize<'de>>(
     r.read_exact(buf).await?;
     Ok(options().deserialize(buf)?)
 }
+
+/// Writes the payload of a value.
+///
+/// blake3 values are bao encoded, so the reader can verify them as they arrive.
+pub async fn write_payload(
+    id: Id<'_>,
+    payload: &[u8],
+    w: &mut (impl AsyncWrite + Unpin),
+) -> Result<(), Box<dyn std::error::Error>> {
+    match id.kind()? {
+        IdKind::Content(HashType::Blake3) => {
+            let outboard = bao::Outboard::new(payload);
+            outboard
+                .write_range(payload, 0..payload.len() as u64, w)
+                .await?;
+        }
+        IdKind::Content(HashType::Sha256) | IdKind::Resource(_) => w.write_all(payload).await?,
+    }
+    Ok(())
+}
+
+/// Reads `len` bytes of payload written by [`write_payload`], checking it is what `id` refers to
+pub async fn read_payload(
+    id: Id<'_>,
+    len: usize,
+    r: &mut (impl AsyncRead + Unpin),
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if len > MAX_VALUE_LEN {
+        return Err(format!("value of {len} bytes is too large").into());
+    }
+    match id.kind()? {
+        IdKind::Content(HashType::Blake3) => {
+            let root = blake3::Hash::from(<[u8; 32]>::try_from(id.hash)?);
+            bao::decode(r, &root, len as u64).await
+        }
+        IdKind::Content(HashType::Sha256) | IdKind::Resource(_) => {
+            let mut payload = Vec::new();
+            r.take(len as u64).read_to_end(&mut payload).await?;
+            if payload.len() != len {
+                return Err("stream finished before value_len bytes".into());
+            }
+            id.verify(&payload)?;
+            Ok(payload)
+        }
+    }
+}
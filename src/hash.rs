@@ -0,0 +1,94 @@
+//! Registry of the hash algorithms an [`Id`](crate::Id) can name.
+//!
+//! blake3 is the default. sha256 is supported because cargo index checksums use it.
+use std::{fmt, io};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum HashType {
+    Blake3,
+    Sha256,
+}
+
+impl HashType {
+    /// Every supported algorithm
+    pub const ALL: [HashType; 2] = [HashType::Blake3, HashType::Sha256];
+
+    /// The algorithm named by an ID's `hash_type`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            HashType::Blake3 => "blake3",
+            HashType::Sha256 => "sha256",
+        }
+    }
+
+    /// Length in bytes of the hashes this algorithm produces
+    pub const fn hash_len(&self) -> usize {
+        match self {
+            HashType::Blake3 => 32,
+            HashType::Sha256 => 32,
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            HashType::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashType::Sha256 => {
+                Hasher::Sha256(Box::new(ring::digest::Context::new(&ring::digest::SHA256)))
+            }
+        }
+    }
+
+    pub fn hash(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finalize()
+    }
+
+    /// Hashes everything `reader` produces
+    pub fn hash_reader(&self, mut reader: impl io::Read) -> io::Result<Vec<u8>> {
+        let mut hasher = self.hasher();
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => return Ok(hasher.finalize()),
+                Ok(n) => hasher.update(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl fmt::Display for HashType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Incremental hashing for any [`HashType`]
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Box<ring::digest::Context>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher) => {
+                hasher.update(bytes);
+            }
+            Hasher::Sha256(context) => context.update(bytes),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+            Hasher::Sha256(context) => context.finish().as_ref().to_vec(),
+        }
+    }
+}
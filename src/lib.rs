@@ -1,20 +1,44 @@
 // yoke does this
 #![allow(clippy::forget_non_drop)]
-use std::{net::SocketAddr, ops::Deref, sync::Arc};
+use std::{collections::BTreeMap, io, net::SocketAddr, ops::Range, sync::Arc, time::Duration};
 
-use encoding::{read_message_fixed, write_message_fixed};
-use quinn::{Endpoint, SendStream};
+use hash::HashType;
+use resources::ResourceType;
+
+use quinn::Endpoint;
 use quinn_proto::ClientConfig;
+use routing::{Contact, Distance, NodeId, K};
 use rustls::{Certificate, PrivateKey};
-use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    task::JoinSet,
+};
 use yoke::{Yoke, Yokeable};
 
-use crate::encoding::{read_message, write_message};
+use crate::encoding::{read_message, read_payload, try_read_message, write_message, write_payload};
 
+pub mod bao;
+pub mod cache;
+pub mod download;
 pub mod encoding;
+pub mod hash;
+pub mod policy;
+pub mod providers;
+pub mod records;
+pub mod resources;
+pub mod routing;
+pub mod store;
 pub mod tls;
+pub mod trust;
+
+/// Number of requests a lookup keeps in flight at once
+pub const ALPHA: usize = 3;
+
+/// How long a lookup waits on a single node before treating it as failed
+pub const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
 
+#[derive(Clone)]
 pub struct Connection {
     inner: quinn::Connection,
 }
@@ -27,6 +51,24 @@ pub enum Requests<'a> {
     FindValue(Id<'a>),
     #[serde(borrow)]
     PutValue(Value<'a>),
+    Ping,
+    #[serde(borrow)]
+    GetRange(ByteRange<'a>),
+    /// Announces that the requester holds the value
+    #[serde(borrow)]
+    AddProvider(Id<'a>),
+    #[serde(borrow)]
+    GetProviders(Id<'a>),
+}
+
+/// A range of the bytes of a blake3 value
+#[derive(Serialize, Deserialize, Yokeable, Clone, Copy)]
+pub struct ByteRange<'a> {
+    #[serde(borrow)]
+    pub id: Id<'a>,
+    pub start: u64,
+    /// Clamped to the length of the value
+    pub end: u64,
 }
 
 #[derive(Serialize, Deserialize, Yokeable, Clone, Copy)]
@@ -34,6 +76,8 @@ pub struct Value<'a> {
     #[serde(borrow)]
     pub id: Id<'a>,
     pub value_len: usize,
+    /// Seconds until the value expires, unless it is published again
+    pub ttl: u64,
 }
 
 #[derive(Serialize, Deserialize, Yokeable, Clone, Copy)]
@@ -42,6 +86,7 @@ pub enum Responses<'a> {
     Location(Location<'a>),
     #[serde(borrow)]
     Value(Value<'a>),
+    Pong,
 }
 
 #[derive(Serialize, Deserialize, Yokeable, Clone, Copy)]
@@ -51,12 +96,430 @@ pub struct Location<'a> {
     pub id: Id<'a>,
 }
 
-#[derive(Serialize, Deserialize, Yokeable, Clone, Copy)]
+/// A hash of some content, under one of the algorithms in [`hash`],
+/// or the key of one of the [`resources`].
+///
+/// IDs with an unknown `hash_type`, or a hash of the wrong length, fail to decode.
+#[derive(Serialize, Yokeable, Clone, Copy, PartialEq, Eq, Debug)]
 pub struct Id<'a> {
     pub hash_type: &'a str,
     pub hash: &'a [u8],
 }
 
+impl<'de: 'a, 'a> Deserialize<'de> for Id<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct RawId<'a> {
+            hash_type: &'a str,
+            hash: &'a [u8],
+        }
+
+        let raw = RawId::deserialize(deserializer)?;
+        let id = Id {
+            hash_type: raw.hash_type,
+            hash: raw.hash,
+        };
+        id.kind().map_err(serde::de::Error::custom)?;
+        Ok(id)
+    }
+}
+
+/// What an [`Id`] refers to
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IdKind {
+    /// Content, under its hash
+    Content(HashType),
+    /// A resource, under the key derived from what identifies it
+    Resource(ResourceType),
+}
+
+/// An owned [`Id`]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct OwnedId {
+    pub hash_type: String,
+    pub hash: Vec<u8>,
+}
+
+impl OwnedId {
+    pub fn id(&self) -> Id<'_> {
+        Id {
+            hash_type: &self.hash_type,
+            hash: &self.hash,
+        }
+    }
+}
+
+impl From<Id<'_>> for OwnedId {
+    fn from(id: Id<'_>) -> Self {
+        Self {
+            hash_type: id.hash_type.to_owned(),
+            hash: id.hash.to_owned(),
+        }
+    }
+}
+
+impl Id<'_> {
+    /// The ID of `bytes` under `hash_type`
+    pub fn compute(hash_type: HashType, bytes: &[u8]) -> OwnedId {
+        OwnedId {
+            hash_type: hash_type.name().to_owned(),
+            hash: hash_type.hash(bytes),
+        }
+    }
+
+    /// The ID of everything `reader` produces, under `hash_type`
+    pub fn compute_reader(hash_type: HashType, reader: impl io::Read) -> io::Result<OwnedId> {
+        Ok(OwnedId {
+            hash_type: hash_type.name().to_owned(),
+            hash: hash_type.hash_reader(reader)?,
+        })
+    }
+
+    /// The ID a value stored under `key` was stored with, if `payload` could have been stored
+    /// under it as content or as a resource
+    pub fn recover(key: &NodeId, payload: &[u8]) -> Option<OwnedId> {
+        let content = HashType::ALL
+            .into_iter()
+            .map(|hash_type| Id::compute(hash_type, payload));
+        let resources = ResourceType::ALL
+            .into_iter()
+            .filter_map(|resource_type| resource_type.identify(payload).ok());
+        content
+            .chain(resources)
+            .find(|id| NodeId::for_id(id.id()) == *key)
+    }
+
+    /// What this ID refers to, if its `hash_type` is registered and the hash is the right length
+    pub fn kind(&self) -> Result<IdKind, String> {
+        let (kind, hash_len) = if let Some(hash_type) = HashType::from_name(self.hash_type) {
+            (IdKind::Content(hash_type), hash_type.hash_len())
+        } else if let Some(resource_type) = ResourceType::from_name(self.hash_type) {
+            (IdKind::Resource(resource_type), resources::KEY_LEN)
+        } else {
+            return Err(format!("unsupported hash type {:?}", self.hash_type));
+        };
+        if self.hash.len() != hash_len {
+            return Err(format!(
+                "{} hashes are {hash_len} bytes, not {}",
+                self.hash_type,
+                self.hash.len()
+            ));
+        }
+        Ok(kind)
+    }
+
+    /// The registered algorithm this ID names, if it is the hash of some content
+    pub fn algorithm(&self) -> Result<HashType, String> {
+        match self.kind()? {
+            IdKind::Content(hash_type) => Ok(hash_type),
+            IdKind::Resource(resource_type) => {
+                Err(format!("{resource_type} ids are not content hashes"))
+            }
+        }
+    }
+
+    /// Checks that `payload` is the content or resource this ID refers to
+    pub fn verify(&self, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match self.kind()? {
+            IdKind::Content(hash_type) => {
+                if hash_type.hash(payload) != self.hash {
+                    return Err(format!("payload does not match its {hash_type} hash").into());
+                }
+                Ok(())
+            }
+            IdKind::Resource(resource_type) => resource_type.verify(*self, payload),
+        }
+    }
+}
+
+/// The node ID of the remote end of a QUIC connection
+pub fn peer_node_id(connection: &quinn::Connection) -> Result<NodeId, Box<dyn std::error::Error>> {
+    let identity = connection
+        .peer_identity()
+        .ok_or("peer did not authenticate")?;
+    let certs = identity
+        .downcast::<Vec<Certificate>>()
+        .map_err(|_| "unexpected peer identity")?;
+    let cert = certs.first().ok_or("peer sent no certificates")?;
+    NodeId::from_certificate(cert)
+}
+
+/// The response to a `FindValue` request
+pub enum FoundValue {
+    Value(Vec<u8>),
+    /// The remote does not hold the value, but these nodes are closer to it
+    Nodes(Vec<Yoke<Location<'static>, Vec<u8>>>),
+}
+
+/// The result of an iterative [`lookup`]
+pub struct Lookup {
+    /// Up to k nodes closest to the target that responded, closest first
+    pub closest: Vec<Contact>,
+    /// Every node that responded
+    pub responded: Vec<Contact>,
+    /// Every node that could not be queried
+    pub failed: Vec<Contact>,
+}
+
+/// Finds the k nodes closest to `target`, starting from `seeds`.
+///
+/// Keeps [`ALPHA`] `FindNode` requests in flight, always to the closest nodes not yet queried.
+/// Finishes once the k closest nodes seen have all responded, since nothing closer can be found.
+pub async fn lookup(
+    endpoint: &Endpoint,
+    target: NodeId,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> Lookup {
+    iterative_lookup(endpoint, target, None, seeds).await.1
+}
+
+/// Finds the value stored under `id`, starting from `seeds`.
+///
+/// Like [`lookup`], but sends `FindValue` requests and follows the closer nodes
+/// returned by peers without the value, until one of them returns it.
+pub async fn find_value(
+    endpoint: &Endpoint,
+    id: Id<'_>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> (Option<Vec<u8>>, Lookup) {
+    let key = Arc::new(OwnedId::from(id));
+    iterative_lookup(endpoint, NodeId::for_id(id), Some(key), seeds).await
+}
+
+/// Finds every copy of the value under `id` held by the k nodes closest to it, starting from `seeds`.
+///
+/// For values merged from many publishers, where one node may only hold some of them.
+/// Fails if none of the closest nodes respond.
+pub async fn find_values(
+    endpoint: &Endpoint,
+    id: Id<'_>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> Result<Vec<Vec<u8>>, Box<dyn std::error::Error>> {
+    let lookup = lookup(endpoint, NodeId::for_id(id), seeds).await;
+
+    let key = Arc::new(OwnedId::from(id));
+    let mut queries = JoinSet::new();
+    for contact in lookup.closest.clone() {
+        let endpoint = endpoint.clone();
+        let key = key.clone();
+        queries.spawn(async move {
+            let result = async {
+                let connection = Connection::dial_contact(&endpoint, &contact).await?;
+                match connection.find_value(key.id()).await? {
+                    FoundValue::Value(value) => Ok(Some(value)),
+                    FoundValue::Nodes(_) => Ok(None),
+                }
+            };
+            let result: Result<_, Box<dyn std::error::Error>> = result.await;
+            (contact, result.map_err(|e| e.to_string()))
+        });
+    }
+
+    let mut values = Vec::new();
+    let mut responded = 0;
+    while let Some(result) = queries.join_next().await {
+        let (contact, result) = result.expect("value query panicked");
+        match result {
+            Ok(value) => {
+                responded += 1;
+                values.extend(value);
+            }
+            Err(e) => eprintln!("value query to {} failed {e}", contact.address),
+        }
+    }
+    if responded == 0 {
+        return Err(format!("none of the nodes closest to {} responded", key.hash_type).into());
+    }
+    Ok(values)
+}
+
+/// Finds the nodes that have announced they hold the value under `id`, starting from `seeds`.
+///
+/// Looks up the k nodes closest to `id`, then asks each of them for the providers they know of.
+pub async fn find_providers(
+    endpoint: &Endpoint,
+    id: Id<'_>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> (Vec<Contact>, Lookup) {
+    let lookup = lookup(endpoint, NodeId::for_id(id), seeds).await;
+
+    let key = Arc::new(OwnedId::from(id));
+    let mut queries = JoinSet::new();
+    for contact in lookup.closest.clone() {
+        let endpoint = endpoint.clone();
+        let key = key.clone();
+        queries.spawn(async move {
+            let result = async {
+                let connection = Connection::dial_contact(&endpoint, &contact).await?;
+                let locations = connection.get_providers(key.id()).await?;
+                locations
+                    .iter()
+                    .map(|location| Contact::try_from(*location.get()))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            let result: Result<_, Box<dyn std::error::Error>> = result.await;
+            (contact, result.map_err(|e| e.to_string()))
+        });
+    }
+
+    let mut providers: Vec<Contact> = Vec::new();
+    while let Some(result) = queries.join_next().await {
+        let (contact, result) = result.expect("provider query panicked");
+        match result {
+            Ok(found) => {
+                for provider in found {
+                    if !providers.iter().any(|p| p.id == provider.id) {
+                        providers.push(provider);
+                    }
+                }
+            }
+            Err(e) => eprintln!("provider query to {} failed {e}", contact.address),
+        }
+    }
+    (providers, lookup)
+}
+
+enum Found {
+    Nodes(Vec<Contact>),
+    Value(Vec<u8>),
+}
+
+async fn iterative_lookup(
+    endpoint: &Endpoint,
+    target: NodeId,
+    key: Option<Arc<OwnedId>>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> (Option<Vec<u8>>, Lookup) {
+    #[derive(PartialEq)]
+    enum State {
+        Waiting,
+        InFlight,
+        Responded,
+        Failed,
+    }
+
+    let mut shortlist = BTreeMap::<Distance, (Contact, State)>::new();
+    for seed in seeds {
+        let distance = seed.id.distance(&target);
+        shortlist.entry(distance).or_insert((seed, State::Waiting));
+    }
+
+    let mut value = None;
+    let mut queries = JoinSet::new();
+    loop {
+        let candidates = shortlist
+            .iter_mut()
+            .filter(|(_, (_, state))| *state != State::Failed)
+            .take(K);
+        for (distance, (contact, state)) in candidates {
+            if queries.len() >= ALPHA {
+                break;
+            }
+            if *state == State::Waiting {
+                *state = State::InFlight;
+                queries.spawn(query(
+                    endpoint.clone(),
+                    *distance,
+                    contact.clone(),
+                    target,
+                    key.clone(),
+                ));
+            }
+        }
+
+        let Some(result) = queries.join_next().await else {
+            break;
+        };
+        let (distance, result) = result.expect("lookup query panicked");
+        let (contact, state) = shortlist.get_mut(&distance).unwrap();
+        match result {
+            Ok(Found::Nodes(found)) => {
+                *state = State::Responded;
+                for contact in found {
+                    let distance = contact.id.distance(&target);
+                    shortlist
+                        .entry(distance)
+                        .or_insert((contact, State::Waiting));
+                }
+            }
+            Ok(Found::Value(found)) => {
+                *state = State::Responded;
+                value = Some(found);
+                break;
+            }
+            Err(e) => {
+                eprintln!("lookup query to {} failed {e}", contact.address);
+                *state = State::Failed;
+            }
+        }
+    }
+
+    let mut lookup = Lookup {
+        closest: vec![],
+        responded: vec![],
+        failed: vec![],
+    };
+    for (contact, state) in shortlist.into_values() {
+        match state {
+            State::Responded => {
+                if lookup.closest.len() < K {
+                    lookup.closest.push(contact.clone());
+                }
+                lookup.responded.push(contact);
+            }
+            State::Failed => lookup.failed.push(contact),
+            State::Waiting | State::InFlight => {}
+        }
+    }
+    (value, lookup)
+}
+
+async fn query(
+    endpoint: Endpoint,
+    distance: Distance,
+    contact: Contact,
+    target: NodeId,
+    key: Option<Arc<OwnedId>>,
+) -> (Distance, Result<Found, String>) {
+    let result = async {
+        let connection = Connection::dial_contact(&endpoint, &contact).await?;
+        let locations = match &key {
+            None => connection.find_node(target.as_id()).await?,
+            Some(key) => match connection.find_value(key.id()).await? {
+                FoundValue::Value(value) => return Ok(Found::Value(value)),
+                FoundValue::Nodes(locations) => locations,
+            },
+        };
+        let contacts = locations
+            .iter()
+            .map(|location| Contact::try_from(*location.get()))
+            .collect::<Result<_, _>>()?;
+        Ok(Found::Nodes(contacts))
+    };
+    let result: Result<_, Box<dyn std::error::Error>> =
+        match tokio::time::timeout(QUERY_TIMEOUT, result).await {
+            Ok(result) => result,
+            Err(_) => Err("timed out".into()),
+        };
+    (distance, result.map_err(|e| e.to_string()))
+}
+
+/// Reads `Location` responses until the remote finishes the stream, at most [`K`] of them
+async fn read_locations(
+    recv: &mut quinn::RecvStream,
+) -> Result<Vec<Yoke<Location<'static>, Vec<u8>>>, Box<dyn std::error::Error>> {
+    let mut locations = Vec::new();
+    while let Some(response) = try_read_message::<Responses>(recv).await? {
+        if locations.len() == K {
+            return Err(format!("remote sent more than {K} locations").into());
+        }
+        locations.push(response.try_map_project(|response, _| match response {
+            Responses::Location(location) => Ok(location),
+            _ => Err("expected a location"),
+        })?);
+    }
+    Ok(locations)
+}
 
 impl Connection {
     pub async fn new(
@@ -80,13 +543,233 @@ impl Connection {
         let mut client = Endpoint::client("0.0.0.0:0".parse()?)?;
         client.set_default_client_config(config);
 
-        let connecting = client.connect(socket, hostname)?;
+        Self::connect(&client, socket, hostname).await
+    }
+
+    /// Connects using the endpoint's default client config.
+    ///
+    /// Peers should dial out of their server endpoint so that the remote
+    /// sees the address they are listening on.
+    pub async fn connect(
+        endpoint: &Endpoint,
+        socket: SocketAddr,
+        hostname: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let connecting = endpoint.connect(socket, hostname)?;
         let connection = connecting.await?;
         println!("connection established {:?}", connection.rtt());
 
         Ok(Self { inner: connection })
     }
 
+    /// Resolves a `host:port` address and connects to it
+    pub async fn dial(
+        endpoint: &Endpoint,
+        address: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let (host, _) = address
+            .rsplit_once(':')
+            .ok_or("address is missing a port")?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let socket = tokio::net::lookup_host(address)
+            .await?
+            .next()
+            .ok_or("address did not resolve")?;
+        Self::connect(endpoint, socket, host).await
+    }
+
+    /// Connects to a node, checking that it authenticates as the ID it was advertised with
+    pub async fn dial_contact(
+        endpoint: &Endpoint,
+        contact: &Contact,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let connection = Self::dial(endpoint, &contact.address).await?;
+        let id = connection.node_id()?;
+        if id != contact.id {
+            connection.inner.close(0u32.into(), b"unexpected node id");
+            return Err(format!(
+                "{} claimed to be {} but authenticated as {id}",
+                contact.address, contact.id
+            )
+            .into());
+        }
+        Ok(connection)
+    }
+
+    pub fn remote_address(&self) -> SocketAddr {
+        self.inner.remote_address()
+    }
+
+    /// The node ID of the remote peer, derived from the certificate it authenticated with
+    pub fn node_id(&self) -> Result<NodeId, Box<dyn std::error::Error>> {
+        peer_node_id(&self.inner)
+    }
+
+    /// Asks the remote for the nodes it knows closest to `id`
+    pub async fn find_node(
+        &self,
+        id: Id<'_>,
+    ) -> Result<Vec<Yoke<Location<'static>, Vec<u8>>>, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        write_message(&Requests::FindNode(id), &mut send).await?;
+        send.finish().await?;
+
+        read_locations(&mut recv).await
+    }
+
+    /// Announces to the remote that we hold the value under `id`.
+    ///
+    /// Returns the contact the remote recorded for us, which carries the address it sees us at.
+    pub async fn add_provider(&self, id: Id<'_>) -> Result<Contact, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        write_message(&Requests::AddProvider(id), &mut send).await?;
+        send.finish().await?;
+
+        let response = read_message::<Responses>(&mut recv).await?;
+        match response.get() {
+            Responses::Location(location) => Contact::try_from(*location),
+            _ => Err("unexpected add_provider response".into()),
+        }
+    }
+
+    /// Asks the remote for the nodes that have announced they hold the value under `id`
+    pub async fn get_providers(
+        &self,
+        id: Id<'_>,
+    ) -> Result<Vec<Yoke<Location<'static>, Vec<u8>>>, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        write_message(&Requests::GetProviders(id), &mut send).await?;
+        send.finish().await?;
+
+        read_locations(&mut recv).await
+    }
+
+    /// Asks the remote for the value stored under `id`,
+    /// or the nodes it knows closest to `id` if it does not hold it
+    pub async fn find_value(&self, id: Id<'_>) -> Result<FoundValue, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        write_message(&Requests::FindValue(id), &mut send).await?;
+        send.finish().await?;
+
+        let mut locations = Vec::new();
+        loop {
+            let response = try_read_message::<Responses>(&mut recv).await?;
+            let Some(response) = response else {
+                break;
+            };
+            match *response.get() {
+                Responses::Value(value) => {
+                    if !locations.is_empty() {
+                        return Err("unexpected value in find_value response".into());
+                    }
+                    if value.id != id {
+                        return Err("find_value responded with a different id".into());
+                    }
+                    let payload = read_payload(id, value.value_len, &mut recv).await?;
+                    return Ok(FoundValue::Value(payload));
+                }
+                Responses::Pong => return Err("unexpected response to find_value".into()),
+                Responses::Location(_) => {
+                    if locations.len() == K {
+                        return Err(format!("remote sent more than {K} locations").into());
+                    }
+                    locations.push(response.map_project(|response, _| match response {
+                        Responses::Location(location) => location,
+                        _ => unreachable!(),
+                    }));
+                }
+            }
+        }
+        Ok(FoundValue::Nodes(locations))
+    }
+
+    /// Fetches `range` of a blake3 value, verifying it against `id` as it arrives.
+    ///
+    /// Returns the length of the whole value, and the content in [`bao::chunk_range`] of `range`.
+    /// The remote finishes the stream without a response if it does not hold the value.
+    pub async fn get_range(
+        &self,
+        id: Id<'_>,
+        range: Range<u64>,
+    ) -> Result<(u64, Vec<u8>), Box<dyn std::error::Error>> {
+        if id.algorithm()? != HashType::Blake3 {
+            return Err("ranges can only be verified for blake3 values".into());
+        }
+        let root = blake3::Hash::from(<[u8; 32]>::try_from(id.hash)?);
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        let request = ByteRange {
+            id,
+            start: range.start,
+            end: range.end,
+        };
+        write_message(&Requests::GetRange(request), &mut send).await?;
+        send.finish().await?;
+
+        let response = read_message::<Responses>(&mut recv).await?;
+        let content_len = match response.get() {
+            Responses::Value(value) if value.id == id => value.value_len as u64,
+            _ => return Err("unexpected get_range response".into()),
+        };
+        let range = range.start.min(content_len)..range.end.min(content_len);
+        let content = bao::decode_range(&mut recv, &root, content_len, range).await?;
+        Ok((content_len, content))
+    }
+
+    /// Checks the remote is responsive, returning the connection's round trip time
+    pub async fn ping(&self) -> Result<Duration, Box<dyn std::error::Error>> {
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        write_message(&Requests::Ping, &mut send).await?;
+        send.finish().await?;
+
+        let response = read_message::<Responses>(&mut recv).await?;
+        match response.get() {
+            Responses::Pong => Ok(self.inner.rtt()),
+            _ => Err("unexpected response to ping".into()),
+        }
+    }
+
+    /// Uploads `value_len` bytes from `body` to be stored under `id` for `ttl`
+    pub async fn put_value(
+        &self,
+        id: Id<'_>,
+        value_len: usize,
+        ttl: Duration,
+        body: impl AsyncRead + Unpin,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        id.kind()?;
+        let (mut send, mut recv) = self.inner.open_bi().await?;
+
+        let value = Value {
+            id,
+            value_len,
+            ttl: ttl.as_secs(),
+        };
+        // the whole body is needed up front to build the tree it is verified against
+        let mut payload = Vec::new();
+        body.take(value_len as u64)
+            .read_to_end(&mut payload)
+            .await?;
+        if payload.len() != value_len {
+            return Err("body ended before value_len bytes".into());
+        }
+        write_message(&Requests::PutValue(value), &mut send).await?;
+        write_payload(id, &payload, &mut send).await?;
+        send.finish().await?;
+
+        // the remote echoes the header back once the value is stored
+        let response = read_message::<Responses>(&mut recv).await?;
+        match response.get() {
+            Responses::Value(value) if value.id == id && value.value_len == value_len => Ok(()),
+            _ => Err("unexpected put_value response".into()),
+        }
+    }
+
     // pub async fn request(
     //     &self,
     //     exchange: &str,
@@ -122,3 +805,42 @@ impl Connection {
     //     Ok(Message { payload, send })
     // }
 }
+
+#[cfg(test)]
+mod tests {
+    use bincode::Options;
+
+    use super::*;
+    use crate::encoding::options;
+
+    #[test]
+    fn compute_matches_the_hash_functions() {
+        let id = Id::compute(HashType::Blake3, b"hello world");
+        assert_eq!(id.hash, blake3::hash(b"hello world").as_bytes());
+        let read = Id::compute_reader(HashType::Blake3, &b"hello world"[..]).unwrap();
+        assert_eq!(read, id);
+        assert!(id.id().verify(b"hello world").is_ok());
+        assert!(id.id().verify(b"hello world!").is_err());
+    }
+
+    #[test]
+    fn deserialize_checks_hash_type_and_length() {
+        let unknown = Id {
+            hash_type: "md5",
+            hash: &[0; 16],
+        };
+        let bytes = options().serialize(&unknown).unwrap();
+        assert!(options().deserialize::<Id>(&bytes).is_err());
+
+        let short = Id {
+            hash_type: "blake3",
+            hash: &[0; 16],
+        };
+        let bytes = options().serialize(&short).unwrap();
+        assert!(options().deserialize::<Id>(&bytes).is_err());
+
+        let id = Id::compute(HashType::Sha256, b"abc");
+        let bytes = options().serialize(&id.id()).unwrap();
+        assert_eq!(options().deserialize::<Id>(&bytes).unwrap(), id.id());
+    }
+}
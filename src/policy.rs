@@ -0,0 +1,404 @@
+//! Install policy: declarative rules a package version must satisfy before it is fetched.
+//!
+//! A policy file is TOML, for example
+//!
+//! ```toml
+//! [trust]
+//! roots = ["<hex user id>"]
+//! max_depth = 2
+//!
+//! [groups]
+//! our-org = ["<hex user id>", "<hex user id>"]
+//!
+//! [[rules]]
+//! name = "tokio-rs needs our reviews"
+//! namespace = "tokio-rs"
+//! from = "our-org"
+//! require = { reviewed = 2 }
+//! allow_pinned = true
+//!
+//! [[rules]]
+//! require = { reviewed = 1 }
+//! ```
+//!
+//! The first rule whose `namespace` and `package` match applies, and a version no rule matches
+//! is denied. A rule counts certifications from the users in its `from` group, or from users
+//! trusted through `[trust]` if it has none. With `allow_pinned`, a version pinned in the
+//! lockfile with the same archive is allowed without certifications.
+//!
+//! A package's namespace comes from its [`Package`] record, which is signed by one of its
+//! owners. The record is ignored if the owner's key has been revoked, or if the rule its
+//! namespace picks does not trust the owner. While the namespace is unknown, a rule with
+//! a `namespace` is taken to apply, and the version is denied.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::Path,
+};
+
+use quinn::Endpoint;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    encoding,
+    hash::HashType,
+    resources::{self, certification::Claim, Package, PackageVersion, Revocations, UserId},
+    routing::Contact,
+    trust::{self, TrustConfig, Verdict},
+    OwnedId,
+};
+
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+pub struct Rule {
+    pub name: String,
+    /// Only applies to packages in this namespace
+    pub namespace: Option<String>,
+    /// Only applies to the package with this name
+    pub package: Option<String>,
+    /// Who the rule trusts, and how many of them must make each claim
+    pub trust: TrustConfig,
+    /// Whether a version pinned in the lockfile is allowed without certifications
+    pub allow_pinned: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    trust: TrustFile,
+    #[serde(default)]
+    groups: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TrustFile {
+    roots: Vec<String>,
+    max_depth: usize,
+}
+
+impl Default for TrustFile {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            max_depth: trust::DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    name: Option<String>,
+    namespace: Option<String>,
+    package: Option<String>,
+    from: Option<String>,
+    #[serde(default)]
+    require: BTreeMap<String, usize>,
+    #[serde(default)]
+    allow_pinned: bool,
+}
+
+impl Policy {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(policy: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: PolicyFile = toml::from_str(policy)?;
+        let roots = parse_users(&file.trust.roots)?;
+
+        let mut rules = Vec::new();
+        for (i, rule) in file.rules.into_iter().enumerate() {
+            let name = rule.name.unwrap_or_else(|| format!("rules[{i}]"));
+            let mut thresholds = BTreeMap::new();
+            for (claim, n) in rule.require {
+                let claim = Claim::from_name(&claim)
+                    .ok_or_else(|| format!("{name} requires unknown claim {claim}"))?;
+                thresholds.insert(claim, n);
+            }
+            // a group is trusted directly, rather than through the web of trust
+            let trust = match rule.from {
+                Some(group) => TrustConfig {
+                    roots: parse_users(
+                        file.groups
+                            .get(&group)
+                            .ok_or_else(|| format!("{name} is from unknown group {group}"))?,
+                    )?,
+                    max_depth: 0,
+                    thresholds,
+                },
+                None => TrustConfig {
+                    roots: roots.clone(),
+                    max_depth: file.trust.max_depth,
+                    thresholds,
+                },
+            };
+            rules.push(Rule {
+                name,
+                namespace: rule.namespace,
+                package: rule.package,
+                trust,
+                allow_pinned: rule.allow_pinned,
+            });
+        }
+        Ok(Self { rules })
+    }
+
+    /// A policy of a single rule for every package
+    pub fn from_trust(trust: TrustConfig) -> Self {
+        Self {
+            rules: vec![Rule {
+                name: "default".to_owned(),
+                namespace: None,
+                package: None,
+                trust,
+                allow_pinned: false,
+            }],
+        }
+    }
+
+    /// The first rule that applies to the package named `name` in `namespace`.
+    ///
+    /// If the namespace is unknown, rules with a `namespace` are taken to apply.
+    pub fn rule(&self, name: &str, namespace: Option<&str>) -> Option<&Rule> {
+        self.rules.iter().find(|rule| {
+            rule.package
+                .as_deref()
+                .map_or(true, |package| package == name)
+                && rule
+                    .namespace
+                    .as_deref()
+                    .zip(namespace)
+                    .map_or(true, |(ns, namespace)| ns == namespace)
+        })
+    }
+
+    /// Decides whether `release` may be installed, fetching the records and certifications
+    /// the applicable rule needs.
+    ///
+    /// Returns the trust verdict the decision was based on, if certifications were checked.
+    pub async fn decide(
+        &self,
+        endpoint: &Endpoint,
+        seeds: Vec<Contact>,
+        release: &PackageVersion,
+        lockfile: &Lockfile,
+    ) -> Result<(Decision, Option<Verdict>), Box<dyn std::error::Error>> {
+        let mut decision = Decision {
+            package: release.package.clone(),
+            version: release.version.to_string(),
+            rule: None,
+            pinned: false,
+            denials: Vec::new(),
+        };
+
+        if let Some(pinned) = lockfile.get(&release.package, &release.version) {
+            if *pinned != release.archive {
+                decision.denials.push(Denial::PinMismatch {
+                    pinned: encoding::to_hex(&pinned.hash),
+                    archive: encoding::to_hex(&release.archive.hash),
+                });
+                return Ok((decision, None));
+            }
+            decision.pinned = true;
+        }
+
+        let key = Package::id_for(&release.package);
+        let mut package: Option<Package> =
+            resources::find(endpoint, key.id(), seeds.clone()).await?;
+        // a record signed with a revoked key says nothing about the package
+        if let Some(signer) = package.as_ref().map(Package::signer) {
+            let key = Revocations::id_for(signer);
+            let revocations: Option<Revocations> =
+                resources::find_merged(endpoint, key.id(), seeds.clone()).await?;
+            if revocations.is_some_and(|revocations| revocations.key_revoked().is_some()) {
+                eprintln!(
+                    "ignoring {} record signed with the revoked key of {signer}",
+                    release.package
+                );
+                package = None;
+            }
+        }
+        let mut namespace = None;
+        if let Some(package) = &package {
+            if self.trusts_namespace(endpoint, &seeds, package).await? {
+                namespace = Some(package.namespace.as_str());
+            } else {
+                eprintln!(
+                    "ignoring {} record signed by {}, who the rule for its namespace does not trust",
+                    package.name,
+                    package.signer()
+                );
+            }
+        }
+        let Some(rule) = self.rule(&release.package, namespace) else {
+            decision.denials.push(Denial::NoRule);
+            return Ok((decision, None));
+        };
+        decision.rule = Some(rule.name.clone());
+        if rule.namespace.is_some() && namespace.is_none() {
+            decision.denials.push(Denial::UnknownNamespace);
+            return Ok((decision, None));
+        }
+        if decision.pinned && rule.allow_pinned {
+            return Ok((decision, None));
+        }
+
+        let (certifications, evidence) =
+            trust::gather(endpoint, seeds, &rule.trust, release).await?;
+        let verdict = rule.trust.evaluate(release, &certifications, &evidence);
+        for claim in &verdict.claims {
+            if claim.paths.len() < claim.required {
+                decision.denials.push(Denial::Certifications {
+                    claim: claim.claim.name().to_owned(),
+                    required: claim.required,
+                    found: claim.paths.len(),
+                });
+            }
+        }
+        Ok((decision, Some(verdict)))
+    }
+
+    /// Whether the namespace `package` is in can be taken from its record: either no rule
+    /// with a `namespace` could apply, or the rule it picks trusts the record's signer
+    async fn trusts_namespace(
+        &self,
+        endpoint: &Endpoint,
+        seeds: &[Contact],
+        package: &Package,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let unknown = self.rule(&package.name, None);
+        if unknown.map_or(true, |rule| rule.namespace.is_none()) {
+            return Ok(true);
+        }
+        let Some(rule) = self.rule(&package.name, Some(&package.namespace)) else {
+            return Ok(true);
+        };
+        let signer = package.signer();
+        if rule.trust.roots.contains(&signer) {
+            return Ok(true);
+        }
+        let evidence = trust::gather_evidence(endpoint, seeds, &rule.trust, [signer]).await?;
+        Ok(rule.trust.trusted(&evidence).contains_key(&signer))
+    }
+}
+
+fn parse_users(users: &[String]) -> Result<Vec<UserId>, Box<dyn std::error::Error>> {
+    users
+        .iter()
+        .map(|user| UserId::from_hex(user).ok_or_else(|| format!("bad user id {user:?}").into()))
+        .collect()
+}
+
+/// The outcome of checking a package version against a [`Policy`], serialised as JSON for CI
+#[derive(Serialize, Debug)]
+pub struct Decision {
+    pub package: String,
+    pub version: String,
+    /// Name of the rule that applied
+    pub rule: Option<String>,
+    /// Whether the version is pinned in the lockfile with the same archive
+    pub pinned: bool,
+    /// Why the version is denied, empty if it is allowed
+    pub denials: Vec<Denial>,
+}
+
+impl Decision {
+    pub fn allowed(&self) -> bool {
+        self.denials.is_empty()
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Denial {
+    /// No rule applies to the package
+    NoRule,
+    /// The rule that applies depends on the package's namespace, and it has no Package record
+    /// signed by a user that rule trusts, with a key that has not been revoked
+    UnknownNamespace,
+    /// The lockfile pins the version to a different archive
+    PinMismatch { pinned: String, archive: String },
+    /// Too few trusted users have made a required claim
+    Certifications {
+        claim: String,
+        required: usize,
+        found: usize,
+    },
+}
+
+/// The package versions a project has pinned, and the archives they were pinned to.
+///
+/// A lockfile is TOML, with a `[[package]]` table of `name`, `version` and blake3 `archive`
+/// hash for each pinned version.
+#[derive(Default)]
+pub struct Lockfile {
+    pins: HashMap<(String, Version), OwnedId>,
+}
+
+#[derive(Deserialize)]
+struct LockfileFile {
+    #[serde(default)]
+    package: Vec<PinFile>,
+}
+
+#[derive(Deserialize)]
+struct PinFile {
+    name: String,
+    version: Version,
+    archive: String,
+}
+
+impl Lockfile {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(lockfile: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let file: LockfileFile = toml::from_str(lockfile)?;
+        let mut pins = HashMap::new();
+        for pin in file.package {
+            let hash = encoding::from_hex::<32>(&pin.archive)
+                .ok_or_else(|| format!("bad archive hash for {}@{}", pin.name, pin.version))?;
+            let archive = OwnedId {
+                hash_type: HashType::Blake3.name().to_owned(),
+                hash: hash.to_vec(),
+            };
+            pins.insert((pin.name, pin.version), archive);
+        }
+        Ok(Self { pins })
+    }
+
+    /// The archive `version` of the package named `name` is pinned to
+    pub fn get(&self, name: &str, version: &Version) -> Option<&OwnedId> {
+        self.pins.get(&(name.to_owned(), version.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Id;
+
+    #[test]
+    fn lockfile_pins_blake3_archives() {
+        let archive = Id::compute(HashType::Blake3, b"serde 1.0.0 archive");
+        let lockfile = Lockfile::parse(&format!(
+            "[[package]]\nname = \"serde\"\nversion = \"1.0.0\"\narchive = \"{}\"\n",
+            encoding::to_hex(&archive.hash)
+        ))
+        .unwrap();
+        let version = Version::new(1, 0, 0);
+        assert_eq!(lockfile.get("serde", &version), Some(&archive));
+
+        let short = "[[package]]\nname = \"serde\"\nversion = \"1.0.0\"\narchive = \"abcd\"\n";
+        assert!(Lockfile::parse(short).is_err());
+    }
+}
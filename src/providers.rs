@@ -0,0 +1,79 @@
+//! Provider records: which nodes hold a value, stored in the DHT in place of the value itself.
+//!
+//! A node announces itself as a provider to the k nodes closest to a value,
+//! and lookups ask those nodes who to download it from.
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    records::REPLICATE_INTERVAL,
+    routing::{Contact, NodeId, K},
+};
+
+/// How long a provider is remembered unless it announces itself again.
+///
+/// Providers announce every [`REPLICATE_INTERVAL`], so one that has gone is forgotten
+/// after missing a couple of announcements.
+pub const PROVIDER_TTL: Duration = Duration::from_secs(3 * REPLICATE_INTERVAL.as_secs());
+
+/// Max number of providers remembered per value, the most recently announced are kept
+pub const MAX_PROVIDERS: usize = K;
+
+/// Max number of values providers are remembered for, so that announcements cannot
+/// grow a node's memory without bound
+pub const MAX_KEYS: usize = 1 << 16;
+
+#[derive(Default)]
+pub struct Providers {
+    /// Providers of each value, least recently announced first
+    providers: HashMap<NodeId, Vec<(Contact, Instant)>>,
+}
+
+impl Providers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `provider` holds the value under `key`.
+    ///
+    /// Returns false if `key` is new and [`MAX_KEYS`] values already have providers.
+    pub fn add(&mut self, key: NodeId, provider: Contact) -> bool {
+        if !self.providers.contains_key(&key) && self.providers.len() >= MAX_KEYS {
+            self.expire();
+            if self.providers.len() >= MAX_KEYS {
+                return false;
+            }
+        }
+        let providers = self.providers.entry(key).or_default();
+        providers.retain(|(contact, _)| contact.id != provider.id);
+        providers.push((provider, Instant::now() + PROVIDER_TTL));
+        if providers.len() > MAX_PROVIDERS {
+            providers.remove(0);
+        }
+        true
+    }
+
+    /// The providers of the value under `key` that have not expired
+    pub fn get(&self, key: &NodeId) -> Vec<Contact> {
+        let now = Instant::now();
+        let Some(providers) = self.providers.get(key) else {
+            return Vec::new();
+        };
+        providers
+            .iter()
+            .filter(|(_, expires)| *expires > now)
+            .map(|(contact, _)| contact.clone())
+            .collect()
+    }
+
+    /// Forgets providers that have expired
+    pub fn expire(&mut self) {
+        let now = Instant::now();
+        self.providers.retain(|_, providers| {
+            providers.retain(|(_, expires)| *expires > now);
+            !providers.is_empty()
+        });
+    }
+}
@@ -0,0 +1,178 @@
+//! Expiry and replication bookkeeping for stored values.
+//!
+//! Every value a node stores is a record with an expiry time. Holders replicate
+//! their records to the k closest nodes periodically, and the original publisher
+//! republishes them to push the expiry back. Records nobody republishes expire.
+//!
+//! Priority records, such as revocations, are replicated before any others.
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{routing::NodeId, Id, IdKind, OwnedId};
+
+/// How long a record lives unless it is published again
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often holders replicate a record to the nodes closest to it
+pub const REPLICATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub id: OwnedId,
+    pub expires: SystemTime,
+    /// When the record was last sent to, or received from, the nodes closest to it
+    pub replicated: SystemTime,
+}
+
+impl Record {
+    /// Time left until the record expires
+    pub fn ttl(&self) -> Duration {
+        self.expires
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// Whether the record is of a [priority](crate::resources::ResourceType::priority) resource type
+    pub fn priority(&self) -> bool {
+        matches!(self.id.id().kind(), Ok(IdKind::Resource(t)) if t.priority())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Records {
+    records: HashMap<NodeId, Record>,
+}
+
+impl Records {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &NodeId) -> Option<&Record> {
+        self.records.get(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Records that a value was published to us with `ttl` left.
+    ///
+    /// The expiry only ever moves later, and is capped at [`MAX_TTL`].
+    /// If publishing it `changed` the value we hold, such as by merging in new certifications,
+    /// it keeps its last replication time so that our next round passes the change on.
+    /// Returns true if we did not hold the record before.
+    pub fn published(&mut self, key: NodeId, id: Id<'_>, ttl: Duration, changed: bool) -> bool {
+        let now = SystemTime::now();
+        let expires = now + ttl.min(MAX_TTL);
+        match self.records.get_mut(&key) {
+            Some(record) => {
+                record.expires = record.expires.max(expires);
+                if !changed {
+                    record.replicated = now;
+                }
+                false
+            }
+            None => {
+                let record = Record {
+                    id: id.into(),
+                    expires,
+                    replicated: now,
+                };
+                self.records.insert(key, record);
+                true
+            }
+        }
+    }
+
+    /// Records that we have replicated a record to the nodes closest to it
+    pub fn replicated(&mut self, key: &NodeId) {
+        if let Some(record) = self.records.get_mut(key) {
+            record.replicated = SystemTime::now();
+        }
+    }
+
+    /// Pushes back the expiry of a record we are republishing as its publisher
+    pub fn refresh(&mut self, key: &NodeId) {
+        if let Some(record) = self.records.get_mut(key) {
+            record.expires = SystemTime::now() + MAX_TTL;
+        }
+    }
+
+    pub fn remove(&mut self, key: &NodeId) -> Option<Record> {
+        self.records.remove(key)
+    }
+
+    /// Keys of every record we hold, priority records first
+    pub fn keys(&self) -> Vec<NodeId> {
+        let mut keys: Vec<_> = self.records.iter().collect();
+        keys.sort_by_key(|(_, r)| !r.priority());
+        keys.into_iter().map(|(key, _)| *key).collect()
+    }
+
+    /// Records not replicated for at least [`REPLICATE_INTERVAL`], priority records first
+    pub fn due_for_replication(&self) -> Vec<(NodeId, Record)> {
+        let now = SystemTime::now();
+        let mut due: Vec<_> = self
+            .records
+            .iter()
+            .filter(|(_, r)| {
+                let since = now.duration_since(r.replicated).unwrap_or_default();
+                since >= REPLICATE_INTERVAL
+            })
+            .map(|(key, r)| (*key, r.clone()))
+            .collect();
+        due.sort_by_key(|(_, r)| !r.priority());
+        due
+    }
+
+    /// Keys of records that have expired
+    pub fn expired(&self) -> Vec<NodeId> {
+        let now = SystemTime::now();
+        self.records
+            .iter()
+            .filter(|(_, r)| r.expires <= now)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::HashType;
+
+    #[test]
+    fn only_unchanged_publishes_count_as_replication() {
+        let id = Id::compute(HashType::Blake3, b"value");
+        let key = NodeId::for_id(id.id());
+        let mut records = Records::new();
+        assert!(records.published(key, id.id(), MAX_TTL, false));
+        records.records.get_mut(&key).unwrap().replicated = SystemTime::UNIX_EPOCH;
+
+        assert!(!records.published(key, id.id(), MAX_TTL, true));
+        assert_eq!(records.due_for_replication().len(), 1);
+
+        assert!(!records.published(key, id.id(), MAX_TTL, false));
+        assert!(records.due_for_replication().is_empty());
+    }
+
+    #[test]
+    fn expiry_only_moves_later() {
+        let id = Id::compute(HashType::Blake3, b"value");
+        let key = NodeId::for_id(id.id());
+        let mut records = Records::new();
+        records.published(key, id.id(), MAX_TTL, false);
+        records.published(key, id.id(), Duration::ZERO, false);
+        assert!(records.expired().is_empty());
+        assert!(records.get(&key).unwrap().ttl() > MAX_TTL / 2);
+    }
+}
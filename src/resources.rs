@@ -0,0 +1,268 @@
+//! Registry metadata stored in the DHT alongside the values it describes.
+//!
+//! A resource is stored under an [`Id`] whose `hash_type` names the resource type,
+//! and whose hash is derived from what identifies the resource, such as a package's name.
+//! A resource is not the content its ID hashes, so nodes check it instead by decoding it
+//! and deriving its ID again.
+use std::fmt;
+
+use bincode::Options;
+use quinn::Endpoint;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    encoding::{self, options},
+    find_value, find_values,
+    routing::Contact,
+    Id, OwnedId,
+};
+
+pub mod certification;
+pub mod package;
+pub mod revocation;
+pub mod signing;
+pub mod user;
+pub mod version;
+
+pub use certification::{Certification, Certifications};
+pub use package::Package;
+pub use revocation::{Revocation, Revocations};
+pub use user::{User, UserKey};
+pub use version::PackageVersion;
+
+/// Length in bytes of the keys resources are stored under
+pub const KEY_LEN: usize = 32;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ResourceType {
+    Package,
+    PackageVersion,
+    User,
+    Certifications,
+    Revocations,
+}
+
+impl ResourceType {
+    /// Every resource type
+    pub const ALL: [ResourceType; 5] = [
+        ResourceType::Package,
+        ResourceType::PackageVersion,
+        ResourceType::User,
+        ResourceType::Certifications,
+        ResourceType::Revocations,
+    ];
+
+    /// The resource type named by an ID's `hash_type`
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            ResourceType::Package => "package",
+            ResourceType::PackageVersion => "package-version",
+            ResourceType::User => "user",
+            ResourceType::Certifications => "certifications",
+            ResourceType::Revocations => "revocations",
+        }
+    }
+
+    /// Whether records of this type are replicated ahead of others
+    pub const fn priority(&self) -> bool {
+        matches!(self, ResourceType::Revocations)
+    }
+
+    /// The blake3 key derivation context for keys of this type,
+    /// so that different resource types never share a key
+    const fn key_context(&self) -> Option<&'static str> {
+        match self {
+            ResourceType::Package => Some("peer2package package key"),
+            ResourceType::PackageVersion => Some("peer2package package-version key"),
+            // users are stored under the blake3 hash of their public key, which is their user ID
+            ResourceType::User => None,
+            ResourceType::Certifications => Some("peer2package certifications key"),
+            ResourceType::Revocations => Some("peer2package revocations key"),
+        }
+    }
+
+    /// The ID a resource of this type identified by `identity` is stored under
+    pub fn id(&self, identity: &[u8]) -> OwnedId {
+        let hash = match self.key_context() {
+            Some(context) => {
+                let mut hasher = blake3::Hasher::new_derive_key(context);
+                hasher.update(identity);
+                hasher.finalize()
+            }
+            None => blake3::hash(identity),
+        };
+        OwnedId {
+            hash_type: self.name().to_owned(),
+            hash: hash.as_bytes().to_vec(),
+        }
+    }
+
+    /// Checks that `payload` is a valid resource of this type that is stored under `id`
+    pub fn verify(&self, id: Id<'_>, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ResourceType::Package => verify::<Package>(id, payload),
+            ResourceType::PackageVersion => verify::<PackageVersion>(id, payload),
+            ResourceType::User => verify::<User>(id, payload),
+            ResourceType::Certifications => verify::<Certifications>(id, payload),
+            ResourceType::Revocations => verify::<Revocations>(id, payload),
+        }
+    }
+
+    /// The ID `payload` is stored under, if it is a valid resource of this type
+    pub fn identify(&self, payload: &[u8]) -> Result<OwnedId, Box<dyn std::error::Error>> {
+        match self {
+            ResourceType::Package => identify::<Package>(payload),
+            ResourceType::PackageVersion => identify::<PackageVersion>(payload),
+            ResourceType::User => identify::<User>(payload),
+            ResourceType::Certifications => identify::<Certifications>(payload),
+            ResourceType::Revocations => identify::<Revocations>(payload),
+        }
+    }
+
+    /// Combines a verified `payload` with the resource of this type already stored under its ID,
+    /// returning what to store in its place
+    pub fn merge(
+        &self,
+        stored: &[u8],
+        payload: Vec<u8>,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self {
+            ResourceType::Package => merge::<Package>(stored, &payload),
+            ResourceType::Certifications => merge::<Certifications>(stored, &payload),
+            ResourceType::Revocations => merge::<Revocations>(stored, &payload),
+            ResourceType::PackageVersion | ResourceType::User => Ok(payload),
+        }
+    }
+}
+
+fn verify<R: Resource>(id: Id<'_>, payload: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let resource = R::decode(payload)?;
+    resource.check()?;
+    if resource.id().id() != id {
+        return Err(format!("{} is not stored under this id", R::TYPE).into());
+    }
+    Ok(())
+}
+
+fn identify<R: Resource>(payload: &[u8]) -> Result<OwnedId, Box<dyn std::error::Error>> {
+    let resource = R::decode(payload)?;
+    resource.check()?;
+    Ok(resource.id())
+}
+
+fn merge<R: Resource>(
+    stored: &[u8],
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut resource = R::decode(stored)?;
+    resource.merge(R::decode(payload)?)?;
+    resource.encode()
+}
+
+impl fmt::Display for ResourceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A record stored in the DHT under an ID derived from what identifies it
+pub trait Resource: Serialize + DeserializeOwned {
+    const TYPE: ResourceType;
+
+    /// What identifies this resource, which its ID is derived from
+    fn identity(&self) -> Vec<u8>;
+
+    /// The ID this resource is stored under
+    fn id(&self) -> OwnedId {
+        Self::TYPE.id(&self.identity())
+    }
+
+    /// Checks the resource is valid on its own, such as its signatures
+    fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Combines `other`, which is stored under the same ID, into this resource.
+    ///
+    /// By default the newer resource replaces the older.
+    fn merge(&mut self, other: Self) -> Result<(), Box<dyn std::error::Error>> {
+        *self = other;
+        Ok(())
+    }
+
+    /// The payload to store this resource as
+    fn encode(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        Ok(options().serialize(self)?)
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(options().deserialize(payload)?)
+    }
+}
+
+/// Finds every copy of the resource stored under `id` held by the nodes closest to it,
+/// and merges them.
+///
+/// Unlike [`find`], a node holding only some of a merged resource, such as a user's
+/// revocations, cannot hide the rest. Fails if none of the closest nodes respond.
+pub async fn find_merged<R: Resource>(
+    endpoint: &Endpoint,
+    id: Id<'_>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> Result<Option<R>, Box<dyn std::error::Error>> {
+    let mut merged: Option<R> = None;
+    for payload in find_values(endpoint, id, seeds).await? {
+        let resource = R::decode(&payload)?;
+        match &mut merged {
+            Some(merged) => merged.merge(resource)?,
+            None => merged = Some(resource),
+        }
+    }
+    Ok(merged)
+}
+
+/// Finds the resource stored under `id`, starting from `seeds`.
+///
+/// Returns `None` if no node holds it.
+pub async fn find<R: Resource>(
+    endpoint: &Endpoint,
+    id: Id<'_>,
+    seeds: impl IntoIterator<Item = Contact>,
+) -> Result<Option<R>, Box<dyn std::error::Error>> {
+    let (payload, _) = find_value(endpoint, id, seeds).await;
+    payload.map(|payload| R::decode(&payload)).transpose()
+}
+
+/// A user, identified by the blake3 hash of their public key
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct UserId(pub [u8; 32]);
+
+impl UserId {
+    pub fn for_key(public_key: &[u8]) -> Self {
+        Self(*blake3::hash(public_key).as_bytes())
+    }
+
+    pub fn to_hex(&self) -> String {
+        encoding::to_hex(&self.0)
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        encoding::from_hex(s).map(Self)
+    }
+}
+
+impl fmt::Debug for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_hex())
+    }
+}
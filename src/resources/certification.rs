@@ -0,0 +1,303 @@
+//! Certifications, users vouching for a package version or for another user.
+//!
+//! Every certification about a subject is stored together under a key derived from the subject,
+//! so one lookup finds them all. Nodes merge the sets they are sent into the set they hold.
+//! A certification carries its signer's public key, so nodes check every signature before
+//! merging, and a certification can only be replaced by a newer one from the same key.
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    signing::{self, Domain},
+    Resource, ResourceType, User, UserId, UserKey,
+};
+use crate::{Id, IdKind, OwnedId};
+
+/// Max number of certifications about one subject.
+///
+/// Anyone can make a key and certify anything, so without a limit a subject's certifications
+/// could be grown until no node can send them.
+pub const MAX_CERTIFICATIONS: usize = 1024;
+
+/// What a certification is about
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum Subject {
+    /// A [`PackageVersion`](super::PackageVersion) with a particular archive.
+    ///
+    /// Version records are not signed, so what is vouched for is the archive, and the record
+    /// by the blake3 hash of its canonical encoding, which covers its dependencies and features.
+    PackageVersion {
+        version: OwnedId,
+        archive: OwnedId,
+        record: [u8; 32],
+    },
+    User(UserId),
+}
+
+impl Subject {
+    /// The ID of the resource the subject refers to
+    pub fn id(&self) -> OwnedId {
+        match self {
+            Subject::PackageVersion { version, .. } => version.clone(),
+            Subject::User(user) => User::id_for(*user),
+        }
+    }
+
+    fn resource_type(&self) -> ResourceType {
+        match self {
+            Subject::PackageVersion { .. } => ResourceType::PackageVersion,
+            Subject::User(_) => ResourceType::User,
+        }
+    }
+}
+
+/// What the signer vouches for
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Claim {
+    /// The signer has reviewed the package version's source
+    Reviewed,
+    /// The signer built the archive from source and got the same bytes
+    BuiltReproducibly,
+    /// The signer has checked the user is who they claim to be
+    TrustedIdentity,
+}
+
+impl Claim {
+    pub const ALL: [Claim; 3] = [
+        Claim::Reviewed,
+        Claim::BuiltReproducibly,
+        Claim::TrustedIdentity,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Claim::Reviewed => "reviewed",
+            Claim::BuiltReproducibly => "built-reproducibly",
+            Claim::TrustedIdentity => "trusted-identity",
+        }
+    }
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Certification {
+    /// Ed25519 public key of the signer
+    pub public_key: [u8; 32],
+    pub subject: Subject,
+    pub claim: Claim,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// Signature of the other fields by the signer's key
+    pub signature: Vec<u8>,
+}
+
+impl Certification {
+    /// Signs `claim` about `subject` with `key`
+    pub fn sign(
+        key: &UserKey,
+        subject: Subject,
+        claim: Claim,
+        timestamp: u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut certification = Self {
+            public_key: key.public_key(),
+            subject,
+            claim,
+            timestamp,
+            signature: Vec::new(),
+        };
+        certification.signature = key.sign(&certification.signed_message()?);
+        Ok(certification)
+    }
+
+    pub fn signer(&self) -> UserId {
+        UserId::for_key(&self.public_key)
+    }
+
+    /// Checks the certification was signed by its public key
+    pub fn verify(&self) -> Result<(), Box<dyn std::error::Error>> {
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, self.public_key)
+            .verify(&self.signed_message()?, &self.signature)
+            .map_err(|_| format!("certification is not signed by user {}", self.signer()))?;
+        Ok(())
+    }
+
+    /// The message the signature covers
+    pub fn signed_message(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let fields = (
+            &self.public_key,
+            &self.subject,
+            &self.claim,
+            &self.timestamp,
+        );
+        signing::message(Domain::Certification, &fields)
+    }
+
+    /// The blake3 hash of the signed message, which identifies the certification in revocations
+    pub fn hash(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(*blake3::hash(&self.signed_message()?).as_bytes())
+    }
+}
+
+/// Every certification about a subject
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Certifications {
+    /// The ID of the package version or user the certifications are about
+    pub subject: OwnedId,
+    /// Sorted by signer then claim, only the latest of each signer's claims is kept
+    pub certifications: Vec<Certification>,
+}
+
+impl Certifications {
+    /// The ID the certifications about the resource `subject` are stored under
+    pub fn id_for(subject: Id<'_>) -> OwnedId {
+        ResourceType::Certifications.id(&subject_identity(subject))
+    }
+
+    pub fn new(subject: OwnedId) -> Self {
+        Self {
+            subject,
+            certifications: Vec::new(),
+        }
+    }
+
+    /// Adds `certification`, replacing any older certification of the same claim by its signer
+    pub fn insert(&mut self, certification: Certification) {
+        let key = |c: &Certification| (c.signer(), c.claim);
+        match self
+            .certifications
+            .binary_search_by_key(&key(&certification), key)
+        {
+            Ok(i) => {
+                if self.certifications[i].timestamp < certification.timestamp {
+                    self.certifications[i] = certification;
+                }
+            }
+            Err(i) => self.certifications.insert(i, certification),
+        }
+    }
+}
+
+impl Resource for Certifications {
+    const TYPE: ResourceType = ResourceType::Certifications;
+
+    fn identity(&self) -> Vec<u8> {
+        subject_identity(self.subject.id())
+    }
+
+    fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.certifications.len() > MAX_CERTIFICATIONS {
+            return Err(format!("more than {MAX_CERTIFICATIONS} certifications").into());
+        }
+        let kind = self.subject.id().kind()?;
+        let valid = [ResourceType::PackageVersion, ResourceType::User]
+            .into_iter()
+            .any(|t| kind == IdKind::Resource(t));
+        if !valid {
+            return Err("certifications must be about a package version or user".into());
+        }
+        for certification in &self.certifications {
+            let subject = &certification.subject;
+            if subject.id() != self.subject || kind != IdKind::Resource(subject.resource_type()) {
+                return Err("certification is about a different subject".into());
+            }
+            certification.verify()?;
+        }
+        let sorted = self
+            .certifications
+            .windows(2)
+            .all(|pair| (pair[0].signer(), pair[0].claim) < (pair[1].signer(), pair[1].claim));
+        if !sorted {
+            return Err("certifications are not sorted by signer and claim".into());
+        }
+        Ok(())
+    }
+
+    /// Fails if the certifications would no longer fit in [`MAX_CERTIFICATIONS`]
+    fn merge(&mut self, other: Self) -> Result<(), Box<dyn std::error::Error>> {
+        for certification in other.certifications {
+            self.insert(certification);
+        }
+        if self.certifications.len() > MAX_CERTIFICATIONS {
+            return Err(format!("more than {MAX_CERTIFICATIONS} certifications").into());
+        }
+        Ok(())
+    }
+}
+
+/// The ID of the subject's resource, as `hash_type`, a zero byte, then the hash
+fn subject_identity(id: Id<'_>) -> Vec<u8> {
+    let mut identity = id.hash_type.as_bytes().to_vec();
+    identity.push(0);
+    identity.extend_from_slice(id.hash);
+    identity
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u32) -> UserKey {
+        let mut seed = [0; 32];
+        seed[..4].copy_from_slice(&i.to_le_bytes());
+        UserKey::from_seed(&seed).unwrap()
+    }
+
+    fn certify(key: &UserKey, subject: UserId, timestamp: u64) -> Certification {
+        Certification::sign(
+            key,
+            Subject::User(subject),
+            Claim::TrustedIdentity,
+            timestamp,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn insert_keeps_the_latest_claim_by_each_signer() {
+        let subject = key(0).user_id();
+        let mut certifications = Certifications::new(User::id_for(subject));
+        certifications.insert(certify(&key(1), subject, 2));
+        certifications.insert(certify(&key(1), subject, 1));
+        certifications.insert(certify(&key(2), subject, 1));
+        assert_eq!(certifications.certifications.len(), 2);
+        assert!(certifications.check().is_ok());
+        let latest = certifications
+            .certifications
+            .iter()
+            .find(|c| c.signer() == key(1).user_id())
+            .unwrap();
+        assert_eq!(latest.timestamp, 2);
+    }
+
+    #[test]
+    fn merge_fails_past_the_limit() {
+        let subject = key(0).user_id();
+        let mut full = Certifications::new(User::id_for(subject));
+        for i in 1..=MAX_CERTIFICATIONS as u32 {
+            full.insert(certify(&key(i), subject, 1));
+        }
+        assert!(full.check().is_ok());
+
+        // a newer certification by a signer already in the set still fits
+        let mut newer = Certifications::new(User::id_for(subject));
+        newer.insert(certify(&key(1), subject, 2));
+        assert!(full.clone().merge(newer).is_ok());
+
+        let mut more = Certifications::new(User::id_for(subject));
+        more.insert(certify(&key(0), subject, 1));
+        let mut merged = full.clone();
+        assert!(merged.merge(more).is_err());
+        assert!(merged.check().is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Package metadata, which is shared by every version of a package.
+//!
+//! A package record is signed by one of its owners. Nodes only let a record signed by a current
+//! owner, and updated later, replace the one they hold, so nobody else can change who owns
+//! a package or which namespace it is in, and an old record cannot be replayed over a newer one.
+use serde::{Deserialize, Serialize};
+
+use super::{
+    signing::{self, Domain},
+    Resource, ResourceType, UserId, UserKey,
+};
+use crate::OwnedId;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Package {
+    /// Unique across the network, the package's key is derived from it
+    pub name: String,
+    /// The organisation or project the package belongs to
+    pub namespace: String,
+    /// Users allowed to publish and update the package
+    pub owners: Vec<UserId>,
+    pub description: String,
+    /// URL of the package's source repository
+    pub repository: Option<String>,
+    /// SPDX license expression
+    pub license: Option<String>,
+    /// Seconds since the unix epoch, later than when the record it replaces was updated
+    pub updated: u64,
+    /// Ed25519 public key of the owner who signed the record
+    pub public_key: [u8; 32],
+    /// Signature of the other fields by `public_key`
+    pub signature: Vec<u8>,
+}
+
+impl Package {
+    /// The ID the package named `name` is stored under
+    pub fn id_for(name: &str) -> OwnedId {
+        ResourceType::Package.id(name.as_bytes())
+    }
+
+    /// Signs the record with `key`, which must be one of its owners
+    pub fn sign(&mut self, key: &UserKey) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.owners.contains(&key.user_id()) {
+            return Err(format!("{} is not an owner of {}", key.user_id(), self.name).into());
+        }
+        self.public_key = key.public_key();
+        self.signature = key.sign(&self.signed_message()?);
+        Ok(())
+    }
+
+    pub fn signer(&self) -> UserId {
+        UserId::for_key(&self.public_key)
+    }
+
+    /// Checks the record was signed by its public key
+    pub fn verify(&self) -> Result<(), Box<dyn std::error::Error>> {
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, self.public_key)
+            .verify(&self.signed_message()?, &self.signature)
+            .map_err(|_| format!("{} is not signed by user {}", self.name, self.signer()))?;
+        Ok(())
+    }
+
+    /// The message the signature covers
+    pub fn signed_message(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let fields = (
+            &self.public_key,
+            &self.name,
+            &self.namespace,
+            &self.owners,
+            &self.description,
+            &self.repository,
+            &self.license,
+            &self.updated,
+        );
+        signing::message(Domain::Package, &fields)
+    }
+}
+
+impl Resource for Package {
+    const TYPE: ResourceType = ResourceType::Package;
+
+    fn identity(&self) -> Vec<u8> {
+        self.name.as_bytes().to_vec()
+    }
+
+    /// Checks the record is signed by one of its owners
+    fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.owners.contains(&self.signer()) {
+            return Err(
+                format!("{} is signed by {}, not an owner", self.name, self.signer()).into(),
+            );
+        }
+        self.verify()
+    }
+
+    /// Only an owner of the package as it stands can replace its record, with a newer one
+    fn merge(&mut self, other: Self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.owners.contains(&other.signer()) && other.updated > self.updated {
+            *self = other;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed(key: &UserKey, owners: Vec<UserId>, namespace: &str, updated: u64) -> Package {
+        let mut package = Package {
+            name: "serde".to_owned(),
+            namespace: namespace.to_owned(),
+            owners,
+            description: String::new(),
+            repository: None,
+            license: None,
+            updated,
+            public_key: [0; 32],
+            signature: Vec::new(),
+        };
+        package.sign(key).unwrap();
+        package
+    }
+
+    #[test]
+    fn merge_only_takes_newer_records_by_owners() {
+        let owner = UserKey::from_seed(&[1; 32]).unwrap();
+        let stranger = UserKey::from_seed(&[2; 32]).unwrap();
+        let mut stored = signed(&owner, vec![owner.user_id()], "serde-rs", 2);
+
+        // an old record replayed by anyone does not replace a newer one
+        let old = signed(&owner, vec![owner.user_id()], "other", 1);
+        stored.merge(old).unwrap();
+        assert_eq!(stored.namespace, "serde-rs");
+
+        let taken = signed(&stranger, vec![stranger.user_id()], "other", 3);
+        assert!(taken.check().is_ok());
+        stored.merge(taken).unwrap();
+        assert_eq!(stored.namespace, "serde-rs");
+
+        let newer = signed(&owner, vec![owner.user_id()], "other", 3);
+        stored.merge(newer).unwrap();
+        assert_eq!(stored.namespace, "other");
+    }
+}
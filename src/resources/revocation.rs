@@ -0,0 +1,168 @@
+//! Revocations, users withdrawing their own key or one of their certifications.
+//!
+//! Every revocation by a user is stored together under a key derived from the user.
+//! Like a certification, a revocation carries the signer's public key, so nodes check its
+//! signature before storing it. Nodes replicate revocations ahead of other records,
+//! but like any record they expire unless the user republishes them.
+//!
+//! Timestamps are chosen by the signer, so whoever stole a key can still sign certifications
+//! dated before its revocation. Revoking those individually rejects them too.
+use serde::{Deserialize, Serialize};
+
+use super::{
+    signing::{self, Domain},
+    Certification, Resource, ResourceType, UserId, UserKey,
+};
+use crate::OwnedId;
+
+/// What is revoked
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Revoked {
+    /// The signer's key, rejecting anything it signed at or after the revocation's timestamp
+    Key,
+    /// One of the signer's certifications, by [`Certification::hash`]
+    Certification([u8; 32]),
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Revocation {
+    /// Ed25519 public key of the revoking user
+    pub public_key: [u8; 32],
+    pub revoked: Revoked,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    /// Signature of the other fields by `public_key`
+    pub signature: Vec<u8>,
+}
+
+impl Revocation {
+    /// Revokes `revoked` with `key`
+    pub fn sign(
+        key: &UserKey,
+        revoked: Revoked,
+        timestamp: u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut revocation = Self {
+            public_key: key.public_key(),
+            revoked,
+            timestamp,
+            signature: Vec::new(),
+        };
+        revocation.signature = key.sign(&revocation.signed_message()?);
+        Ok(revocation)
+    }
+
+    pub fn signer(&self) -> UserId {
+        UserId::for_key(&self.public_key)
+    }
+
+    /// Checks the revocation was signed by its public key
+    pub fn verify(&self) -> Result<(), Box<dyn std::error::Error>> {
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, self.public_key)
+            .verify(&self.signed_message()?, &self.signature)
+            .map_err(|_| format!("revocation is not signed by user {}", self.signer()))?;
+        Ok(())
+    }
+
+    /// The message the signature covers
+    pub fn signed_message(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let fields = (&self.public_key, &self.revoked, &self.timestamp);
+        signing::message(Domain::Revocation, &fields)
+    }
+}
+
+/// Every revocation by a user
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Revocations {
+    pub user: UserId,
+    /// Sorted by what is revoked, with only the earliest revocation of the key kept
+    pub revocations: Vec<Revocation>,
+}
+
+impl Revocations {
+    /// The ID the revocations by `user` are stored under
+    pub fn id_for(user: UserId) -> OwnedId {
+        ResourceType::Revocations.id(&user.0)
+    }
+
+    pub fn new(user: UserId) -> Self {
+        Self {
+            user,
+            revocations: Vec::new(),
+        }
+    }
+
+    /// Adds `revocation`, keeping the earlier if it revokes something already revoked
+    pub fn insert(&mut self, revocation: Revocation) {
+        match self
+            .revocations
+            .binary_search_by_key(&revocation.revoked, |r| r.revoked)
+        {
+            Ok(i) => {
+                if revocation.timestamp < self.revocations[i].timestamp {
+                    self.revocations[i] = revocation;
+                }
+            }
+            Err(i) => self.revocations.insert(i, revocation),
+        }
+    }
+
+    /// When the user's key was revoked, if it has been
+    pub fn key_revoked(&self) -> Option<u64> {
+        self.revocations
+            .iter()
+            .find(|r| r.revoked == Revoked::Key)
+            .map(|r| r.timestamp)
+    }
+
+    /// Whether `certification` is rejected by these revocations
+    pub fn rejects(&self, certification: &Certification) -> bool {
+        if certification.signer() != self.user {
+            return false;
+        }
+        if self
+            .key_revoked()
+            .is_some_and(|revoked| certification.timestamp >= revoked)
+        {
+            return true;
+        }
+        let Ok(hash) = certification.hash() else {
+            return true;
+        };
+        self.revocations
+            .iter()
+            .any(|r| r.revoked == Revoked::Certification(hash))
+    }
+}
+
+impl Resource for Revocations {
+    const TYPE: ResourceType = ResourceType::Revocations;
+
+    fn identity(&self) -> Vec<u8> {
+        self.user.0.to_vec()
+    }
+
+    fn check(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for revocation in &self.revocations {
+            if revocation.signer() != self.user {
+                return Err("revocation is by a different user".into());
+            }
+            revocation.verify()?;
+        }
+        let sorted = self
+            .revocations
+            .windows(2)
+            .all(|pair| pair[0].revoked < pair[1].revoked);
+        if !sorted {
+            return Err("revocations are not sorted by what they revoke".into());
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, other: Self) -> Result<(), Box<dyn std::error::Error>> {
+        for revocation in other.revocations {
+            self.insert(revocation);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! Package@Version records, which resolve a version of a package to its archive.
+use std::collections::BTreeMap;
+
+use bincode::Options;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+
+use super::{certification::Subject, signing, Package, Resource, ResourceType};
+use crate::OwnedId;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PackageVersion {
+    /// Name of the [`Package`] this is a version of
+    pub package: String,
+    pub version: Version,
+    /// ID of the package archive, which is stored in the DHT as a value
+    pub archive: OwnedId,
+    pub dependencies: Vec<Dependency>,
+    /// Each feature, and the features and optional dependencies it enables
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Seconds since the unix epoch
+    pub published: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Dependency {
+    /// Name of the depended on package
+    pub package: String,
+    pub req: VersionReq,
+    /// Features of the dependency to enable
+    pub features: Vec<String>,
+    /// Only depended on when a feature enables it
+    pub optional: bool,
+}
+
+impl PackageVersion {
+    /// The ID `version` of the package named `name` is stored under
+    pub fn id_for(name: &str, version: &Version) -> OwnedId {
+        ResourceType::PackageVersion.id(format!("{name}@{version}").as_bytes())
+    }
+
+    /// Parses `name@version`, the form versions are resolved from
+    pub fn parse_spec(spec: &str) -> Result<(&str, Version), Box<dyn std::error::Error>> {
+        let (name, version) = spec.rsplit_once('@').ok_or("expected name@version")?;
+        Ok((name, version.parse()?))
+    }
+
+    /// What certifications of exactly this record are about
+    pub fn subject(&self) -> Result<Subject, Box<dyn std::error::Error>> {
+        Ok(Subject::PackageVersion {
+            version: self.id(),
+            archive: self.archive.clone(),
+            record: self.hash()?,
+        })
+    }
+
+    /// The blake3 hash of the record's [canonical](signing::canonical) encoding
+    pub fn hash(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        let encoded = signing::canonical().serialize(self)?;
+        Ok(*blake3::hash(&encoded).as_bytes())
+    }
+
+    /// The ID of the [`Package`] this is a version of
+    pub fn package_id(&self) -> OwnedId {
+        Package::id_for(&self.package)
+    }
+}
+
+impl Resource for PackageVersion {
+    const TYPE: ResourceType = ResourceType::PackageVersion;
+
+    fn identity(&self) -> Vec<u8> {
+        format!("{}@{}", self.package, self.version).into_bytes()
+    }
+}
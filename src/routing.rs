@@ -0,0 +1,407 @@
+//! Kademlia routing table.
+//!
+//! Nodes live in a 256-bit keyspace of blake3 hashes. Contacts are sorted into
+//! k-buckets by the XOR distance between their ID and our own.
+use std::{
+    collections::VecDeque,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use rustls::Certificate;
+use serde::{Deserialize, Serialize};
+
+use crate::{encoding, hash::HashType, Id, Location};
+
+/// Max number of contacts per bucket
+pub const K: usize = 20;
+
+/// The hash type used for node IDs and keyspace positions
+pub const NODE_ID_HASH_TYPE: &str = HashType::Blake3.name();
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NodeId(pub [u8; 32]);
+
+impl NodeId {
+    /// The node ID of a peer authenticated with this certificate.
+    ///
+    /// This is the blake3 hash of its public key, so a node cannot choose
+    /// its position in the keyspace without also holding the matching private key.
+    pub fn from_certificate(cert: &Certificate) -> Result<Self, Box<dyn std::error::Error>> {
+        let spki = crate::tls::subject_public_key_info(cert)?;
+        Ok(Self(*blake3::hash(spki).as_bytes()))
+    }
+
+    /// The position of an arbitrary [`Id`] in the keyspace.
+    ///
+    /// blake3 IDs are used as is, anything else is hashed into the keyspace.
+    pub fn for_id(id: Id<'_>) -> Self {
+        match Self::try_from(id) {
+            Ok(node_id) => node_id,
+            Err(_) => {
+                let mut hasher = blake3::Hasher::new();
+                hasher.update(id.hash_type.as_bytes());
+                hasher.update(&[0]);
+                hasher.update(id.hash);
+                Self(*hasher.finalize().as_bytes())
+            }
+        }
+    }
+
+    pub fn as_id(&self) -> Id<'_> {
+        Id {
+            hash_type: NODE_ID_HASH_TYPE,
+            hash: &self.0,
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        encoding::to_hex(&self.0)
+    }
+
+    pub fn from_hex(s: &str) -> Option<Self> {
+        encoding::from_hex(s).map(Self)
+    }
+
+    pub fn distance(&self, other: &NodeId) -> Distance {
+        let mut d = [0; 32];
+        for (d, (a, b)) in d.iter_mut().zip(self.0.iter().zip(&other.0)) {
+            *d = a ^ b;
+        }
+        Distance(d)
+    }
+}
+
+impl TryFrom<Id<'_>> for NodeId {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(id: Id<'_>) -> Result<Self, Self::Error> {
+        if id.hash_type != NODE_ID_HASH_TYPE {
+            return Err(format!("node IDs must be {NODE_ID_HASH_TYPE} hashes").into());
+        }
+        Ok(Self(id.hash.try_into()?))
+    }
+}
+
+impl fmt::Debug for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for NodeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.0[..8] {
+            write!(f, "{b:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// XOR distance between two points in the keyspace
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Distance(pub [u8; 32]);
+
+impl Distance {
+    /// The bucket that a node at this distance belongs in.
+    /// Bucket `i` covers distances in `[2^i, 2^(i+1))`.
+    ///
+    /// Returns `None` for the zero distance, which is only ourselves.
+    pub fn bucket_index(&self) -> Option<usize> {
+        let mut leading_zeros = 0;
+        for b in self.0 {
+            leading_zeros += b.leading_zeros() as usize;
+            if b != 0 {
+                break;
+            }
+        }
+        255usize.checked_sub(leading_zeros)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub id: NodeId,
+    pub address: String,
+}
+
+impl Contact {
+    pub fn location(&self) -> Location<'_> {
+        Location {
+            address: &self.address,
+            id: self.id.as_id(),
+        }
+    }
+}
+
+impl TryFrom<Location<'_>> for Contact {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(location: Location<'_>) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: NodeId::try_from(location.id)?,
+            address: location.address.to_owned(),
+        })
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Insert {
+    /// The contact was added to its bucket
+    Inserted,
+    /// The contact was already known and is now the most recently seen
+    Updated,
+    /// The bucket is full. `oldest` is the least recently seen contact,
+    /// which should be evicted if it is no longer responsive.
+    Full { oldest: Contact },
+    /// The contact is ourselves
+    Local,
+}
+
+struct Entry {
+    contact: Contact,
+    last_seen: Instant,
+    rtt: Option<Duration>,
+}
+
+pub struct RoutingTable {
+    local: NodeId,
+    /// Each bucket is ordered from least to most recently seen
+    buckets: Vec<VecDeque<Entry>>,
+}
+
+impl RoutingTable {
+    pub fn new(local: NodeId) -> Self {
+        Self {
+            local,
+            buckets: (0..256).map(|_| VecDeque::new()).collect(),
+        }
+    }
+
+    pub fn local_id(&self) -> NodeId {
+        self.local
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Records that we have seen this contact
+    pub fn insert(&mut self, contact: Contact) -> Insert {
+        let Some(index) = self.local.distance(&contact.id).bucket_index() else {
+            return Insert::Local;
+        };
+        let bucket = &mut self.buckets[index];
+
+        if let Some(pos) = bucket.iter().position(|e| e.contact.id == contact.id) {
+            let mut entry = bucket.remove(pos).unwrap();
+            entry.contact = contact;
+            entry.last_seen = Instant::now();
+            bucket.push_back(entry);
+            return Insert::Updated;
+        }
+
+        if bucket.len() >= K {
+            return Insert::Full {
+                oldest: bucket[0].contact.clone(),
+            };
+        }
+
+        bucket.push_back(Entry {
+            contact,
+            last_seen: Instant::now(),
+            rtt: None,
+        });
+        Insert::Inserted
+    }
+
+    /// Records a round trip time measured to a known contact
+    pub fn record_rtt(&mut self, id: &NodeId, rtt: Duration) {
+        if let Some(entry) = self.entry_mut(id) {
+            entry.rtt = Some(rtt);
+        }
+    }
+
+    /// The last round trip time measured to a contact
+    pub fn rtt(&self, id: &NodeId) -> Option<Duration> {
+        self.entry(id)?.rtt
+    }
+
+    pub fn remove(&mut self, id: &NodeId) -> Option<Contact> {
+        let index = self.local.distance(id).bucket_index()?;
+        let bucket = &mut self.buckets[index];
+        let pos = bucket.iter().position(|e| e.contact.id == *id)?;
+        bucket.remove(pos).map(|e| e.contact)
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<&Contact> {
+        Some(&self.entry(id)?.contact)
+    }
+
+    fn entry(&self, id: &NodeId) -> Option<&Entry> {
+        let index = self.local.distance(id).bucket_index()?;
+        self.buckets[index].iter().find(|e| e.contact.id == *id)
+    }
+
+    fn entry_mut(&mut self, id: &NodeId) -> Option<&mut Entry> {
+        let index = self.local.distance(id).bucket_index()?;
+        self.buckets[index].iter_mut().find(|e| e.contact.id == *id)
+    }
+
+    /// Every known contact
+    pub fn contacts(&self) -> impl Iterator<Item = &Contact> {
+        self.buckets.iter().flatten().map(|e| &e.contact)
+    }
+
+    /// Up to `count` known contacts, closest to `target` first
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut contacts: Vec<_> = self.contacts().cloned().collect();
+        contacts.sort_by_key(|c| c.id.distance(target));
+        contacts.truncate(count);
+        contacts
+    }
+
+    /// Contacts that have not been seen for at least `idle`
+    pub fn stale(&self, idle: Duration) -> Vec<Contact> {
+        self.buckets
+            .iter()
+            .flatten()
+            .filter(|e| e.last_seen.elapsed() >= idle)
+            .map(|e| e.contact.clone())
+            .collect()
+    }
+
+    /// Non-empty buckets where no contact has been seen for at least `idle`
+    pub fn idle_buckets(&self, idle: Duration) -> Vec<usize> {
+        (0..self.buckets.len())
+            .filter(|&i| {
+                let newest = self.buckets[i].back();
+                newest.is_some_and(|e| e.last_seen.elapsed() >= idle)
+            })
+            .collect()
+    }
+
+    /// An ID that falls in bucket `index`, with the bits below the bucket's prefix taken from `random`
+    pub fn id_in_bucket(&self, index: usize, random: [u8; 32]) -> NodeId {
+        let mut distance = [0; 32];
+        let byte = 31 - index / 8;
+        let bit = 1u8 << (index % 8);
+        distance[byte] = bit | (random[byte] & (bit - 1));
+        distance[byte + 1..].copy_from_slice(&random[byte + 1..]);
+        NodeId(self.local.distance(&NodeId(distance)).0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contact(id: NodeId) -> Contact {
+        Contact {
+            id,
+            address: "127.0.0.1:5000".to_owned(),
+        }
+    }
+
+    /// An ID at `distance` from the zero ID
+    fn at(distance: [u8; 32]) -> NodeId {
+        NodeId(distance)
+    }
+
+    #[test]
+    fn bucket_index_is_the_highest_set_bit() {
+        let mut d = [0; 32];
+        assert_eq!(Distance(d).bucket_index(), None);
+        d[31] = 1;
+        assert_eq!(Distance(d).bucket_index(), Some(0));
+        d[31] = 0b1010;
+        assert_eq!(Distance(d).bucket_index(), Some(3));
+        d[30] = 1;
+        assert_eq!(Distance(d).bucket_index(), Some(8));
+        d[0] = 0x80;
+        assert_eq!(Distance(d).bucket_index(), Some(255));
+    }
+
+    #[test]
+    fn insert_updates_known_contacts_and_reports_full_buckets() {
+        let local = NodeId([0; 32]);
+        let mut routing = RoutingTable::new(local);
+        assert_eq!(routing.insert(contact(local)), Insert::Local);
+        assert!(routing.is_empty());
+
+        // every ID with the top bit set falls in bucket 255
+        let ids: Vec<_> = (0..=K as u8)
+            .map(|i| {
+                let mut id = [0; 32];
+                id[0] = 0x80 | i;
+                at(id)
+            })
+            .collect();
+        for id in &ids[..K] {
+            assert_eq!(routing.insert(contact(*id)), Insert::Inserted);
+        }
+        assert_eq!(routing.len(), K);
+        assert_eq!(
+            routing.insert(contact(ids[K])),
+            Insert::Full {
+                oldest: contact(ids[0])
+            }
+        );
+
+        // seeing the oldest again moves it to the back, and keeps its new address
+        let mut moved = contact(ids[0]);
+        moved.address = "127.0.0.1:6000".to_owned();
+        assert_eq!(routing.insert(moved.clone()), Insert::Updated);
+        assert_eq!(routing.get(&ids[0]), Some(&moved));
+        assert_eq!(
+            routing.insert(contact(ids[K])),
+            Insert::Full {
+                oldest: contact(ids[1])
+            }
+        );
+
+        routing.remove(&ids[1]);
+        assert_eq!(routing.insert(contact(ids[K])), Insert::Inserted);
+        assert_eq!(routing.len(), K);
+    }
+
+    #[test]
+    fn id_in_bucket_falls_in_that_bucket() {
+        let local = NodeId(*blake3::hash(b"local").as_bytes());
+        let routing = RoutingTable::new(local);
+        for random in [[0; 32], [0xff; 32], *blake3::hash(b"random").as_bytes()] {
+            for index in [0, 1, 7, 8, 9, 100, 254, 255] {
+                let id = routing.id_in_bucket(index, random);
+                assert_eq!(local.distance(&id).bucket_index(), Some(index));
+            }
+        }
+    }
+
+    #[test]
+    fn stale_and_idle_follow_last_seen() {
+        let mut routing = RoutingTable::new(NodeId([0; 32]));
+        routing.insert(contact(NodeId([1; 32])));
+        assert!(routing.stale(Duration::from_secs(60)).is_empty());
+        assert_eq!(routing.stale(Duration::ZERO), [contact(NodeId([1; 32]))]);
+        assert!(routing.idle_buckets(Duration::from_secs(60)).is_empty());
+        assert_eq!(routing.idle_buckets(Duration::ZERO), [248]);
+    }
+
+    #[test]
+    fn closest_sorts_by_distance_to_the_target() {
+        let mut routing = RoutingTable::new(NodeId([0; 32]));
+        for i in 1..=5u8 {
+            routing.insert(contact(NodeId([i; 32])));
+        }
+        let closest: Vec<_> = routing
+            .closest(&NodeId([4; 32]), 3)
+            .into_iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(closest, [NodeId([4; 32]), NodeId([5; 32]), NodeId([1; 32])]);
+    }
+}
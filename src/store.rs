@@ -0,0 +1,194 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::routing::NodeId;
+
+/// Blob storage, keyed by position in the keyspace
+pub trait Store: Send + Sync {
+    fn get(&self, key: &NodeId) -> io::Result<Option<Vec<u8>>>;
+
+    /// The bytes in `range` of the value under `key`, if there is one
+    fn get_range(&self, key: &NodeId, range: Range<u64>) -> io::Result<Option<Vec<u8>>> {
+        let Some(value) = self.get(key)? else {
+            return Ok(None);
+        };
+        let range = range.start as usize..range.end as usize;
+        match value.get(range) {
+            Some(bytes) => Ok(Some(bytes.to_vec())),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    /// Stores `value` under `key`, replacing any existing value
+    fn put(&self, key: &NodeId, value: &[u8]) -> io::Result<()>;
+
+    /// Removes the value under `key`, if there is one
+    fn remove(&self, key: &NodeId) -> io::Result<()>;
+
+    fn contains(&self, key: &NodeId) -> io::Result<bool> {
+        Ok(self.get(key)?.is_some())
+    }
+
+    /// The length of the value under `key`, if there is one
+    fn size(&self, key: &NodeId) -> io::Result<Option<u64>> {
+        Ok(self.get(key)?.map(|value| value.len() as u64))
+    }
+
+    /// Every key that currently has a value
+    fn keys(&self) -> io::Result<Vec<NodeId>>;
+}
+
+#[derive(Default)]
+pub struct MemoryStore {
+    values: Mutex<HashMap<NodeId, Vec<u8>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &NodeId) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn put(&self, key: &NodeId, value: &[u8]) -> io::Result<()> {
+        self.values.lock().unwrap().insert(*key, value.to_vec());
+        Ok(())
+    }
+
+    fn remove(&self, key: &NodeId) -> io::Result<()> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn get_range(&self, key: &NodeId, range: Range<u64>) -> io::Result<Option<Vec<u8>>> {
+        let values = self.values.lock().unwrap();
+        let Some(value) = values.get(key) else {
+            return Ok(None);
+        };
+        match value.get(range.start as usize..range.end as usize) {
+            Some(bytes) => Ok(Some(bytes.to_vec())),
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn contains(&self, key: &NodeId) -> io::Result<bool> {
+        Ok(self.values.lock().unwrap().contains_key(key))
+    }
+
+    fn size(&self, key: &NodeId) -> io::Result<Option<u64>> {
+        let values = self.values.lock().unwrap();
+        Ok(values.get(key).map(|value| value.len() as u64))
+    }
+
+    fn keys(&self) -> io::Result<Vec<NodeId>> {
+        Ok(self.values.lock().unwrap().keys().copied().collect())
+    }
+}
+
+/// Content addressed storage on disk.
+///
+/// Each value is a file named by the hex of its key,
+/// fanned out into directories by the first two bytes: `ab/cd/abcd...`
+pub struct DiskStore {
+    root: PathBuf,
+}
+
+impl DiskStore {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &NodeId) -> PathBuf {
+        let hex = key.to_hex();
+        self.root.join(&hex[0..2]).join(&hex[2..4]).join(hex)
+    }
+}
+
+impl Store for DiskStore {
+    fn get(&self, key: &NodeId) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(key)) {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn get_range(&self, key: &NodeId, range: Range<u64>) -> io::Result<Option<Vec<u8>>> {
+        let mut file = match fs::File::open(self.path(key)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        file.seek(SeekFrom::Start(range.start))?;
+        let mut bytes = vec![0; (range.end - range.start) as usize];
+        file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    fn put(&self, key: &NodeId, value: &[u8]) -> io::Result<()> {
+        let path = self.path(key);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        // write then rename, so readers never see a partial value
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, value)?;
+        fs::rename(tmp, path)
+    }
+
+    fn remove(&self, key: &NodeId) -> io::Result<()> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn contains(&self, key: &NodeId) -> io::Result<bool> {
+        self.path(key).try_exists()
+    }
+
+    fn size(&self, key: &NodeId) -> io::Result<Option<u64>> {
+        match fs::metadata(self.path(key)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn keys(&self) -> io::Result<Vec<NodeId>> {
+        let mut keys = Vec::new();
+        for a in read_dirs(&self.root)? {
+            for b in read_dirs(&a)? {
+                for entry in fs::read_dir(b)? {
+                    let name = entry?.file_name();
+                    if let Some(key) = name.to_str().and_then(NodeId::from_hex) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        Ok(keys)
+    }
+}
+
+fn read_dirs(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            dirs.push(entry.path());
+        }
+    }
+    Ok(dirs)
+}
@@ -5,6 +5,7 @@ use rustls::{
     ClientConfig, PrivateKey, RootCertStore, ServerConfig,
 };
 
+#[allow(dead_code)]
 fn ca_store(
     ca_certs: impl IntoIterator<Item = Certificate>,
 ) -> Result<RootCertStore, rustls::Error> {
@@ -53,12 +54,12 @@ struct NoServerVerify;
 impl ServerCertVerifier for NoServerVerify {
     fn verify_server_cert(
         &self,
-        end_entity: &Certificate,
-        intermediates: &[Certificate],
-        server_name: &rustls::ServerName,
-        scts: &mut dyn Iterator<Item = &[u8]>,
-        ocsp_response: &[u8],
-        now: std::time::SystemTime,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
     ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
         Ok(rustls::client::ServerCertVerified::assertion())
     }
@@ -72,10 +73,62 @@ impl ClientCertVerifier for NoClientVerify {
 
     fn verify_client_cert(
         &self,
-        end_entity: &Certificate,
-        intermediates: &[Certificate],
-        now: std::time::SystemTime,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: std::time::SystemTime,
     ) -> Result<rustls::server::ClientCertVerified, rustls::Error> {
         Ok(rustls::server::ClientCertVerified::assertion())
     }
 }
+
+/// The DER encoded SubjectPublicKeyInfo of an X.509 certificate
+pub fn subject_public_key_info(cert: &Certificate) -> Result<&[u8], Box<dyn std::error::Error>> {
+    Ok(find_spki(&cert.0).ok_or("malformed certificate")?)
+}
+
+fn find_spki(cert: &[u8]) -> Option<&[u8]> {
+    const SEQUENCE: u8 = 0x30;
+    const VERSION: u8 = 0xa0;
+
+    // Certificate ::= SEQUENCE { tbsCertificate, signatureAlgorithm, signatureValue }
+    let (certificate, _) = der_element(cert, SEQUENCE)?;
+    let (tbs, _) = der_element(certificate, SEQUENCE)?;
+
+    // TBSCertificate ::= SEQUENCE { version [0] OPTIONAL, serialNumber, signature,
+    //     issuer, validity, subject, subjectPublicKeyInfo, ... }
+    let mut rest = tbs;
+    if rest.first() == Some(&VERSION) {
+        (_, rest) = der_element(rest, VERSION)?;
+    }
+    for _ in 0..5 {
+        (_, rest) = der_element(rest, *rest.first()?)?;
+    }
+    let (_, after) = der_element(rest, SEQUENCE)?;
+    Some(&rest[..rest.len() - after.len()])
+}
+
+/// Splits the next DER element off `input`, checking its tag.
+/// Returns its contents and the remaining input.
+fn der_element(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let [actual, len, rest @ ..] = input else {
+        return None;
+    };
+    if *actual != tag {
+        return None;
+    }
+
+    let (len, rest) = match *len {
+        len @ 0..=0x7f => (len as usize, rest),
+        len @ 0x81..=0x84 => {
+            let n = (len & 0x7f) as usize;
+            let len = rest
+                .get(..n)?
+                .iter()
+                .fold(0, |len, b| len << 8 | *b as usize);
+            (len, &rest[n..])
+        }
+        _ => return None,
+    };
+
+    Some((rest.get(..len)?, &rest[len..]))
+}
@@ -0,0 +1,552 @@
+//! Web of trust: deciding whether to accept a package version from the certifications about it.
+//!
+//! Root users are trusted outright. A user certified as `trusted-identity` by a trusted user
+//! is trusted too, up to [`TrustConfig::max_depth`] certifications away from a root.
+//! A version is accepted once enough trusted users have made each required claim about it.
+//!
+//! Certifications a user has revoked are ignored, as is anything signed with a revoked key
+//! at or after the time it was revoked.
+//! Revocations are merged from every node closest to them, so one node cannot hide some of
+//! them, and nothing is trusted if they cannot be looked up.
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use quinn::Endpoint;
+use tokio::task::JoinSet;
+
+use crate::{
+    resources::{
+        self,
+        certification::{Claim, Subject},
+        Certification, Certifications, PackageVersion, Resource, Revocations, User, UserId,
+    },
+    routing::Contact,
+    OwnedId,
+};
+
+/// Default [`TrustConfig::max_depth`]
+pub const DEFAULT_MAX_DEPTH: usize = 2;
+
+/// Max number of users [`gather`] looks up the certifications of at each step back from a version.
+///
+/// Anyone can certify anything, so the signers come from whoever published certifications.
+pub const MAX_FRONTIER: usize = 256;
+
+/// Max number of lookups [`gather`] runs at once
+pub const MAX_CONCURRENT_LOOKUPS: usize = 16;
+
+pub struct TrustConfig {
+    /// Users trusted without being certified by anyone
+    pub roots: Vec<UserId>,
+    /// Max number of `trusted-identity` certifications between a root and a trusted user
+    pub max_depth: usize,
+    /// Number of trusted users that must make each claim about a version for it to be accepted
+    pub thresholds: BTreeMap<Claim, usize>,
+}
+
+/// The certifications and revocations trust is evaluated over, usually gathered with [`gather`]
+#[derive(Default)]
+pub struct Evidence {
+    /// Certifications about each user
+    certifications: HashMap<UserId, Vec<Certification>>,
+    /// Revocations by each user
+    revocations: HashMap<UserId, Revocations>,
+}
+
+impl Evidence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the certifications about a user
+    pub fn add_certifications(&mut self, certifications: Certifications) {
+        for certification in certifications.certifications {
+            if let Subject::User(user) = certification.subject {
+                self.certifications
+                    .entry(user)
+                    .or_default()
+                    .push(certification);
+            }
+        }
+    }
+
+    /// Adds the revocations by a user, after checking their signatures
+    pub fn add_revocations(
+        &mut self,
+        revocations: Revocations,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        revocations.check()?;
+        match self.revocations.get_mut(&revocations.user) {
+            Some(known) => known.merge(revocations)?,
+            None => {
+                self.revocations.insert(revocations.user, revocations);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the certification's signature checks out, and the signer has not revoked it
+    /// or their key
+    fn verified(&self, certification: &Certification) -> bool {
+        let revoked = self
+            .revocations
+            .get(&certification.signer())
+            .is_some_and(|revocations| revocations.rejects(certification));
+        !revoked && certification.verify().is_ok()
+    }
+}
+
+/// The users from a root to a trusted user, each certifying the next as `trusted-identity`
+#[derive(Clone, Debug)]
+pub struct TrustPath(pub Vec<UserId>);
+
+impl TrustPath {
+    /// The user this path establishes trust in
+    pub fn user(&self) -> UserId {
+        *self.0.last().expect("trust paths start at a root")
+    }
+}
+
+/// Whether a version is accepted, and why
+#[derive(Debug)]
+pub struct Verdict {
+    pub claims: Vec<ClaimVerdict>,
+}
+
+#[derive(Debug)]
+pub struct ClaimVerdict {
+    pub claim: Claim,
+    pub required: usize,
+    /// How each trusted user that made the claim is trusted
+    pub paths: Vec<TrustPath>,
+}
+
+impl Verdict {
+    pub fn accepted(&self) -> bool {
+        self.claims
+            .iter()
+            .all(|claim| claim.paths.len() >= claim.required)
+    }
+}
+
+impl TrustConfig {
+    /// Every user trusted given `evidence`, with the shortest path of certifications to them
+    pub fn trusted(&self, evidence: &Evidence) -> HashMap<UserId, TrustPath> {
+        // who each user has certified as trusted-identity, from signatures that check out
+        let mut certified: HashMap<UserId, Vec<UserId>> = HashMap::new();
+        for (user, certifications) in &evidence.certifications {
+            for certification in certifications {
+                if certification.claim == Claim::TrustedIdentity && evidence.verified(certification)
+                {
+                    certified
+                        .entry(certification.signer())
+                        .or_default()
+                        .push(*user);
+                }
+            }
+        }
+
+        let mut trusted = HashMap::new();
+        let mut queue = VecDeque::new();
+        for root in &self.roots {
+            trusted.insert(*root, TrustPath(vec![*root]));
+            queue.push_back(*root);
+        }
+        while let Some(user) = queue.pop_front() {
+            let path = trusted[&user].clone();
+            if path.0.len() > self.max_depth {
+                continue;
+            }
+            for next in certified.get(&user).into_iter().flatten() {
+                if !trusted.contains_key(next) {
+                    let mut path = path.clone();
+                    path.0.push(*next);
+                    trusted.insert(*next, path);
+                    queue.push_back(*next);
+                }
+            }
+        }
+        trusted
+    }
+
+    /// Decides whether `release` is acceptable, given the `certifications` about it
+    pub fn evaluate(
+        &self,
+        release: &PackageVersion,
+        certifications: &Certifications,
+        evidence: &Evidence,
+    ) -> Verdict {
+        let trusted = self.trusted(evidence);
+        let subject = release.subject().ok();
+        let claims = self
+            .thresholds
+            .iter()
+            .map(|(&claim, &required)| {
+                let paths = certifications
+                    .certifications
+                    .iter()
+                    // certifications of a different archive or record say nothing about this one
+                    .filter(|c| c.claim == claim && Some(&c.subject) == subject.as_ref())
+                    .filter(|c| evidence.verified(c))
+                    .filter_map(|c| trusted.get(&c.signer()).cloned())
+                    .collect();
+                ClaimVerdict {
+                    claim,
+                    required,
+                    paths,
+                }
+            })
+            .collect();
+        Verdict { claims }
+    }
+}
+
+/// Fetches the certifications about `release`, and the [`gather_evidence`] needed to decide
+/// whether their signers are trusted under `config`.
+pub async fn gather(
+    endpoint: &Endpoint,
+    seeds: Vec<Contact>,
+    config: &TrustConfig,
+    release: &PackageVersion,
+) -> Result<(Certifications, Evidence), Box<dyn std::error::Error>> {
+    let subject = release.id();
+    let key = Certifications::id_for(subject.id());
+    let certifications = resources::find(endpoint, key.id(), seeds.clone())
+        .await?
+        .unwrap_or_else(|| Certifications::new(subject));
+
+    // only the signers of certifications that could count towards the verdict matter
+    let subject = release.subject().ok();
+    let signers = certifications
+        .certifications
+        .iter()
+        .filter(|c| config.thresholds.contains_key(&c.claim))
+        .filter(|c| Some(&c.subject) == subject.as_ref())
+        .map(Certification::signer);
+    let evidence = gather_evidence(endpoint, &seeds, config, signers).await?;
+    Ok((certifications, evidence))
+}
+
+/// Fetches the certifications and revocations needed to decide whether `users` are trusted
+/// under `config`.
+///
+/// Works back from them through the users who certified them, up to `max_depth` steps,
+/// following at most [`MAX_FRONTIER`] users at each step.
+pub async fn gather_evidence(
+    endpoint: &Endpoint,
+    seeds: &[Contact],
+    config: &TrustConfig,
+    users: impl IntoIterator<Item = UserId>,
+) -> Result<Evidence, Box<dyn std::error::Error>> {
+    let mut evidence = Evidence::new();
+    let mut seen: HashSet<UserId> = config.roots.iter().copied().collect();
+    let mut frontier: Vec<UserId> = users
+        .into_iter()
+        .filter(|user| seen.insert(*user))
+        .collect();
+    // everyone whose revocations could matter
+    let mut users: Vec<UserId> = config.roots.clone();
+
+    // roots need no certifications, everyone else is certified by someone closer to a root
+    for _ in 0..config.max_depth {
+        truncate_frontier(&mut frontier);
+        users.extend(&frontier);
+        let ids = frontier
+            .iter()
+            .map(|user| Certifications::id_for(User::id_for(*user).id()));
+        let found: Vec<Certifications> = find_all(endpoint, seeds, ids).await;
+        frontier.clear();
+        for set in found {
+            for certification in &set.certifications {
+                if certification.claim == Claim::TrustedIdentity
+                    && seen.insert(certification.signer())
+                {
+                    frontier.push(certification.signer());
+                }
+            }
+            evidence.add_certifications(set);
+        }
+    }
+    truncate_frontier(&mut frontier);
+    users.extend(&frontier);
+
+    for revocations in find_revocations(endpoint, seeds, users).await? {
+        let id = revocations.user;
+        if let Err(e) = evidence.add_revocations(revocations) {
+            eprintln!("ignoring revocations by {id} {e}");
+        }
+    }
+    Ok(evidence)
+}
+
+/// Finds the revocations by each of `users`, [`MAX_CONCURRENT_LOOKUPS`] at a time,
+/// merged from every node holding them.
+///
+/// Fails if the revocations by any of them cannot be looked up, rather than trusting keys
+/// that might have been revoked.
+async fn find_revocations(
+    endpoint: &Endpoint,
+    seeds: &[Contact],
+    users: Vec<UserId>,
+) -> Result<Vec<Revocations>, Box<dyn std::error::Error>> {
+    let seeds: Arc<[Contact]> = seeds.into();
+    let mut queries = JoinSet::new();
+    let mut found = Vec::new();
+    for user in users {
+        if queries.len() >= MAX_CONCURRENT_LOOKUPS {
+            if let Some(result) = queries.join_next().await {
+                found.extend(found_revocations(result)?);
+            }
+        }
+        let endpoint = endpoint.clone();
+        let seeds = seeds.clone();
+        queries.spawn(async move {
+            let id = Revocations::id_for(user);
+            let result = resources::find_merged(&endpoint, id.id(), seeds.iter().cloned()).await;
+            (user, result.map_err(|e| e.to_string()))
+        });
+    }
+
+    while let Some(result) = queries.join_next().await {
+        found.extend(found_revocations(result)?);
+    }
+    Ok(found)
+}
+
+/// The revocations a [`find_revocations`] query found, if any
+fn found_revocations(
+    result: Result<(UserId, Result<Option<Revocations>, String>), tokio::task::JoinError>,
+) -> Result<Option<Revocations>, Box<dyn std::error::Error>> {
+    let (user, result) = result.expect("revocations query panicked");
+    result.map_err(|e| format!("could not fetch the revocations by {user} {e}").into())
+}
+
+fn truncate_frontier(frontier: &mut Vec<UserId>) {
+    if frontier.len() > MAX_FRONTIER {
+        eprintln!(
+            "only following {MAX_FRONTIER} of {} certifying users",
+            frontier.len()
+        );
+        frontier.truncate(MAX_FRONTIER);
+    }
+}
+
+/// Finds the resources stored under `ids`, [`MAX_CONCURRENT_LOOKUPS`] at a time,
+/// skipping any that are not found
+async fn find_all<R: Resource + Send + 'static>(
+    endpoint: &Endpoint,
+    seeds: &[Contact],
+    ids: impl IntoIterator<Item = OwnedId>,
+) -> Vec<R> {
+    let seeds: Arc<[Contact]> = seeds.into();
+    let mut queries = JoinSet::new();
+    let mut found = Vec::new();
+    for id in ids {
+        if queries.len() >= MAX_CONCURRENT_LOOKUPS {
+            if let Some(result) = queries.join_next().await {
+                found.extend(found_resource(result));
+            }
+        }
+        let endpoint = endpoint.clone();
+        let seeds = seeds.clone();
+        queries.spawn(async move {
+            let result = resources::find::<R>(&endpoint, id.id(), seeds.iter().cloned()).await;
+            (id, result.map_err(|e| e.to_string()))
+        });
+    }
+
+    while let Some(result) = queries.join_next().await {
+        found.extend(found_resource(result));
+    }
+    found
+}
+
+/// The resource a [`find_all`] query found, if any
+fn found_resource<R>(
+    result: Result<(OwnedId, Result<Option<R>, String>), tokio::task::JoinError>,
+) -> Option<R> {
+    let (id, result) = result.expect("resource query panicked");
+    match result {
+        Ok(resource) => resource,
+        Err(e) => {
+            eprintln!("could not fetch {} {e}", id.hash_type);
+            None
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for claim in &self.claims {
+            writeln!(
+                f,
+                "{}: {} of {} required",
+                claim.claim,
+                claim.paths.len(),
+                claim.required
+            )?;
+            for path in &claim.paths {
+                writeln!(f, "  {path}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TrustPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, user) in self.0.iter().enumerate() {
+            if i == 0 {
+                write!(f, "root {user}")?;
+            } else {
+                write!(f, " certified {user}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hash::HashType,
+        resources::{revocation::Revoked, version::Dependency, Revocation, UserKey},
+        Id,
+    };
+
+    fn key(i: u8) -> UserKey {
+        UserKey::from_seed(&[i; 32]).unwrap()
+    }
+
+    fn release() -> PackageVersion {
+        PackageVersion {
+            package: "serde".to_owned(),
+            version: "1.0.0".parse().unwrap(),
+            archive: Id::compute(HashType::Blake3, b"archive"),
+            dependencies: Vec::new(),
+            features: BTreeMap::new(),
+            published: 1,
+        }
+    }
+
+    fn certify(signer: &UserKey, subject: Subject, claim: Claim) -> Certification {
+        Certification::sign(signer, subject, claim, 10).unwrap()
+    }
+
+    /// `keys[0]` certifies `keys[1]`, who certifies `keys[2]`, who reviewed `release`
+    fn chain(keys: &[UserKey], release: &PackageVersion) -> (Certifications, Evidence) {
+        let mut evidence = Evidence::new();
+        for pair in keys.windows(2) {
+            let subject = Subject::User(pair[1].user_id());
+            let mut set = Certifications::new(subject.id());
+            set.insert(certify(&pair[0], subject, Claim::TrustedIdentity));
+            evidence.add_certifications(set);
+        }
+        let mut reviews = Certifications::new(release.id());
+        reviews.insert(certify(
+            &keys[2],
+            release.subject().unwrap(),
+            Claim::Reviewed,
+        ));
+        (reviews, evidence)
+    }
+
+    fn config(root: &UserKey, max_depth: usize) -> TrustConfig {
+        TrustConfig {
+            roots: vec![root.user_id()],
+            max_depth,
+            thresholds: BTreeMap::from([(Claim::Reviewed, 1)]),
+        }
+    }
+
+    #[test]
+    fn trust_follows_certifications_up_to_max_depth() {
+        let keys = [key(1), key(2), key(3)];
+        let (_, evidence) = chain(&keys, &release());
+
+        let trusted = config(&keys[0], 2).trusted(&evidence);
+        let path = &trusted[&keys[2].user_id()];
+        let users: Vec<_> = keys.iter().map(UserKey::user_id).collect();
+        assert_eq!(path.0, users);
+
+        let trusted = config(&keys[0], 1).trusted(&evidence);
+        assert!(trusted.contains_key(&keys[1].user_id()));
+        assert!(!trusted.contains_key(&keys[2].user_id()));
+    }
+
+    #[test]
+    fn evaluate_counts_reviews_by_trusted_users() {
+        let keys = [key(1), key(2), key(3)];
+        let release = release();
+        let (reviews, evidence) = chain(&keys, &release);
+        assert!(config(&keys[0], 2)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+        assert!(!config(&keys[0], 1)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+        assert!(!config(&key(4), 2)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+    }
+
+    #[test]
+    fn reviews_of_a_different_record_do_not_count() {
+        let keys = [key(1), key(2), key(3)];
+        let release = release();
+        let (reviews, evidence) = chain(&keys, &release);
+
+        let mut other_archive = release.clone();
+        other_archive.archive = Id::compute(HashType::Blake3, b"evil");
+        let mut injected = release.clone();
+        injected.dependencies.push(Dependency {
+            package: "evil".to_owned(),
+            req: "*".parse().unwrap(),
+            features: Vec::new(),
+            optional: false,
+        });
+        for other in [other_archive, injected] {
+            assert!(!config(&keys[0], 2)
+                .evaluate(&other, &reviews, &evidence)
+                .accepted());
+        }
+    }
+
+    #[test]
+    fn revocations_reject_certifications() {
+        let keys = [key(1), key(2), key(3)];
+        let release = release();
+
+        // the middle user's key was revoked before they certified the reviewer
+        let (reviews, mut evidence) = chain(&keys, &release);
+        let mut revocations = Revocations::new(keys[1].user_id());
+        revocations.insert(Revocation::sign(&keys[1], Revoked::Key, 5).unwrap());
+        evidence.add_revocations(revocations).unwrap();
+        assert!(!config(&keys[0], 2)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+
+        // the reviewer withdrew their review
+        let (reviews, mut evidence) = chain(&keys, &release);
+        let hash = reviews.certifications[0].hash().unwrap();
+        let mut revocations = Revocations::new(keys[2].user_id());
+        revocations.insert(Revocation::sign(&keys[2], Revoked::Certification(hash), 20).unwrap());
+        evidence.add_revocations(revocations).unwrap();
+        assert!(!config(&keys[0], 2)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+
+        // a key revoked after the certifications were made leaves them standing
+        let (reviews, mut evidence) = chain(&keys, &release);
+        let mut revocations = Revocations::new(keys[1].user_id());
+        revocations.insert(Revocation::sign(&keys[1], Revoked::Key, 50).unwrap());
+        evidence.add_revocations(revocations).unwrap();
+        assert!(config(&keys[0], 2)
+            .evaluate(&release, &reviews, &evidence)
+            .accepted());
+    }
+}
@@ -0,0 +1,290 @@
+//! Checks the signing test vectors in `test-vectors/signing.txt` against this implementation.
+//!
+//! Run with `GENERATE_SIGNING_VECTORS=1` to rewrite the vectors instead.
+use std::{collections::HashMap, error::Error, fmt::Write};
+
+use peer2package::{
+    encoding::to_hex,
+    hash::HashType,
+    resources::{
+        certification::{Claim, Subject},
+        revocation::Revoked,
+        Certification, Package, PackageVersion, ResourceType, Revocation, User, UserId, UserKey,
+    },
+    Id, OwnedId,
+};
+
+const VECTORS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-vectors/signing.txt");
+
+/// The private key seeds of RFC 8032 section 7.1 tests 1 and 2
+const SEEDS: [&str; 2] = [
+    "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+    "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+];
+
+#[test]
+fn vectors_match() -> Result<(), Box<dyn Error>> {
+    if std::env::var_os("GENERATE_SIGNING_VECTORS").is_some() {
+        std::fs::write(VECTORS, generate()?)?;
+        return Ok(());
+    }
+
+    let vectors = std::fs::read_to_string(VECTORS)?;
+    let mut checked = 0;
+    for block in vectors.split("\n\n") {
+        let mut fields: HashMap<&str, Vec<&str>> = HashMap::new();
+        for line in block.lines().filter(|line| !line.starts_with('#')) {
+            let (key, value) = line.split_once(" = ").ok_or("expected key = value")?;
+            fields.entry(key).or_default().push(value);
+        }
+        if fields.is_empty() {
+            continue;
+        }
+        let field = |key: &str| -> Result<&str, Box<dyn Error>> {
+            Ok(fields
+                .get(key)
+                .and_then(|v| v.first())
+                .ok_or(format!("missing {key}"))?)
+        };
+
+        let key = UserKey::from_seed(&hex(field("seed")?)?.try_into().map_err(|_| "bad seed")?)?;
+        let (message, signature) = match field("type")? {
+            "user" => {
+                let contact = fields.get("contact").cloned().unwrap_or_default();
+                let contact = contact.into_iter().map(String::from).collect();
+                let user = User::new(&key, field("name")?.to_owned(), contact)?;
+                if to_hex(&user.public_key) != field("public_key")? {
+                    return Err("public key does not match".into());
+                }
+                user.verify(&user.signed_message()?, &user.signature)?;
+                (user.signed_message()?, user.signature)
+            }
+            "certification" => {
+                let subject: [u8; 32] = hex(field("subject")?)?
+                    .try_into()
+                    .map_err(|_| "bad subject")?;
+                let subject = match field("subject_type")? {
+                    "package-version" => Subject::PackageVersion {
+                        version: OwnedId {
+                            hash_type: ResourceType::PackageVersion.name().to_owned(),
+                            hash: subject.to_vec(),
+                        },
+                        archive: OwnedId {
+                            hash_type: HashType::Blake3.name().to_owned(),
+                            hash: hex(field("archive")?)?,
+                        },
+                        record: hex(field("record")?)?
+                            .try_into()
+                            .map_err(|_| "bad record hash")?,
+                    },
+                    "user" => Subject::User(UserId(subject)),
+                    other => return Err(format!("unknown subject type {other}").into()),
+                };
+                let claim = Claim::from_name(field("claim")?).ok_or("unknown claim")?;
+                let timestamp = field("timestamp")?.parse()?;
+                let certification = Certification::sign(&key, subject, claim, timestamp)?;
+                if to_hex(&certification.public_key) != field("public_key")? {
+                    return Err("public key does not match".into());
+                }
+                certification.verify()?;
+                (certification.signed_message()?, certification.signature)
+            }
+            "revocation" => {
+                let revoked = match field("revoked")? {
+                    "key" => Revoked::Key,
+                    "certification" => Revoked::Certification(
+                        hex(field("certification")?)?
+                            .try_into()
+                            .map_err(|_| "bad certification hash")?,
+                    ),
+                    other => return Err(format!("unknown revoked {other}").into()),
+                };
+                let timestamp = field("timestamp")?.parse()?;
+                let revocation = Revocation::sign(&key, revoked, timestamp)?;
+                if to_hex(&revocation.public_key) != field("public_key")? {
+                    return Err("public key does not match".into());
+                }
+                revocation.verify()?;
+                (revocation.signed_message()?, revocation.signature)
+            }
+            "package" => {
+                let owners = fields.get("owner").cloned().unwrap_or_default();
+                let owners = owners
+                    .into_iter()
+                    .map(|owner| UserId::from_hex(owner).ok_or("bad owner"))
+                    .collect::<Result<_, _>>()?;
+                let optional = |key: &str| fields.get(key).map(|v| v[0].to_owned());
+                let mut package = Package {
+                    name: field("name")?.to_owned(),
+                    namespace: field("namespace")?.to_owned(),
+                    owners,
+                    description: field("description")?.to_owned(),
+                    repository: optional("repository"),
+                    license: optional("license"),
+                    updated: field("updated")?.parse()?,
+                    public_key: [0; 32],
+                    signature: Vec::new(),
+                };
+                package.sign(&key)?;
+                if to_hex(&package.public_key) != field("public_key")? {
+                    return Err("public key does not match".into());
+                }
+                package.verify()?;
+                (package.signed_message()?, package.signature)
+            }
+            other => return Err(format!("unknown type {other}").into()),
+        };
+        if to_hex(&message) != field("message")? {
+            return Err(format!("message of vector {checked} does not match").into());
+        }
+        if to_hex(&signature) != field("signature")? {
+            return Err(format!("signature of vector {checked} does not match").into());
+        }
+        checked += 1;
+    }
+    assert!(checked > 0, "no vectors in {VECTORS}");
+    // the file has every vector this implementation generates, and nothing else
+    assert_eq!(vectors, generate()?);
+    Ok(())
+}
+
+fn generate() -> Result<String, Box<dyn Error>> {
+    let keys = SEEDS
+        .iter()
+        .map(|seed| UserKey::from_seed(&hex(seed)?.try_into().map_err(|_| "bad seed")?))
+        .collect::<Result<Vec<_>, Box<dyn Error>>>()?;
+
+    let mut out = String::from(
+        "# Signing test vectors, see src/resources/signing.rs for the encoding.\n\
+         # Seeds are Ed25519 private keys, those of RFC 8032 section 7.1 tests 1 and 2.\n",
+    );
+    let users = [("alice", vec!["mailto:alice@example.com"]), ("Zoë", vec![])];
+    for ((name, contact), (seed, key)) in users.into_iter().zip(SEEDS.iter().zip(&keys)) {
+        let contact = contact.into_iter().map(String::from).collect();
+        let user = User::new(key, name.to_owned(), contact)?;
+        writeln!(out, "\ntype = user\nseed = {seed}")?;
+        writeln!(
+            out,
+            "public_key = {}\nname = {}",
+            to_hex(&user.public_key),
+            user.name
+        )?;
+        for contact in &user.contact {
+            writeln!(out, "contact = {contact}")?;
+        }
+        writeln!(out, "message = {}", to_hex(&user.signed_message()?))?;
+        writeln!(out, "signature = {}", to_hex(&user.signature))?;
+    }
+
+    let version = PackageVersion {
+        package: "serde".to_owned(),
+        version: "1.0.0".parse()?,
+        archive: Id::compute(HashType::Blake3, b"serde 1.0.0 archive"),
+        dependencies: Vec::new(),
+        features: Default::default(),
+        published: 1_700_000_000,
+    }
+    .subject()?;
+    let certifications = [
+        (0, version.clone(), Claim::Reviewed),
+        (1, version, Claim::BuiltReproducibly),
+        (1, Subject::User(keys[0].user_id()), Claim::TrustedIdentity),
+    ];
+    let mut hashes = Vec::new();
+    for (signer, subject, claim) in certifications {
+        let certification = Certification::sign(&keys[signer], subject, claim, 1_700_000_000)?;
+        hashes.push(certification.hash()?);
+        writeln!(out, "\ntype = certification\nseed = {}", SEEDS[signer])?;
+        writeln!(out, "public_key = {}", to_hex(&certification.public_key))?;
+        match &certification.subject {
+            Subject::PackageVersion {
+                version,
+                archive,
+                record,
+            } => {
+                writeln!(
+                    out,
+                    "# serde@1.0.0, with the blake3 hash of \"serde 1.0.0 archive\", \
+                     published at 1700000000 with no dependencies or features"
+                )?;
+                writeln!(out, "subject_type = package-version")?;
+                writeln!(out, "subject = {}", to_hex(&version.hash))?;
+                writeln!(out, "archive = {}", to_hex(&archive.hash))?;
+                writeln!(out, "record = {}", to_hex(record))?;
+            }
+            Subject::User(user) => writeln!(out, "subject_type = user\nsubject = {user}")?,
+        }
+        writeln!(
+            out,
+            "claim = {}\ntimestamp = {}",
+            claim, certification.timestamp
+        )?;
+        writeln!(
+            out,
+            "message = {}",
+            to_hex(&certification.signed_message()?)
+        )?;
+        writeln!(out, "signature = {}", to_hex(&certification.signature))?;
+    }
+
+    // the second signer withdraws their built-reproducibly certification, the first their key
+    let revocations = [(1, Revoked::Certification(hashes[1])), (0, Revoked::Key)];
+    for (signer, revoked) in revocations {
+        let revocation = Revocation::sign(&keys[signer], revoked, 1_700_000_100)?;
+        writeln!(out, "\ntype = revocation\nseed = {}", SEEDS[signer])?;
+        writeln!(out, "public_key = {}", to_hex(&revocation.public_key))?;
+        match revoked {
+            Revoked::Key => writeln!(out, "revoked = key")?,
+            Revoked::Certification(hash) => {
+                writeln!(out, "# the blake3 hash of the third vector's message")?;
+                writeln!(out, "revoked = certification")?;
+                writeln!(out, "certification = {}", to_hex(&hash))?;
+            }
+        }
+        writeln!(out, "timestamp = {}", revocation.timestamp)?;
+        writeln!(out, "message = {}", to_hex(&revocation.signed_message()?))?;
+        writeln!(out, "signature = {}", to_hex(&revocation.signature))?;
+    }
+
+    // the second user updates a package both users own
+    let mut package = Package {
+        name: "serde".to_owned(),
+        namespace: "serde-rs".to_owned(),
+        owners: vec![keys[0].user_id(), keys[1].user_id()],
+        description: "A serialization framework".to_owned(),
+        repository: Some("https://github.com/serde-rs/serde".to_owned()),
+        license: None,
+        updated: 1_700_000_000,
+        public_key: [0; 32],
+        signature: Vec::new(),
+    };
+    package.sign(&keys[1])?;
+    writeln!(out, "\ntype = package\nseed = {}", SEEDS[1])?;
+    writeln!(out, "public_key = {}", to_hex(&package.public_key))?;
+    writeln!(
+        out,
+        "name = {}\nnamespace = {}",
+        package.name, package.namespace
+    )?;
+    for owner in &package.owners {
+        writeln!(out, "owner = {owner}")?;
+    }
+    writeln!(out, "description = {}", package.description)?;
+    if let Some(repository) = &package.repository {
+        writeln!(out, "repository = {repository}")?;
+    }
+    writeln!(out, "updated = {}", package.updated)?;
+    writeln!(out, "message = {}", to_hex(&package.signed_message()?))?;
+    writeln!(out, "signature = {}", to_hex(&package.signature))?;
+    Ok(out)
+}
+
+fn hex(s: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if s.len() % 2 != 0 {
+        return Err("odd number of hex characters".into());
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| Ok(u8::from_str_radix(&s[i..i + 2], 16)?))
+        .collect()
+}